@@ -7,7 +7,7 @@ pub fn check_password(password: &str) -> Option<&'static str> {
 }
 pub async fn check_email(email: &str) -> Option<&str> {
     if email.len() <= 64 {
-        if let None = mysql::get_like("users", "email", email).await.get(0) {
+        if let None = crate::db::get_where("users", "email", email).await.get(0) {
             None
         } else {
             Some("Sorry, your email address has already been registered. Please use a different email address or log in with your account.")
@@ -17,11 +17,10 @@ pub async fn check_email(email: &str) -> Option<&str> {
     }
 }
 
+/// Accepts only the policies `0`, `1` and `2` exactly, so `-0` or `+1` are not stored.
 pub fn check_subscription(subscription: &str) -> Option<&str> {
-    if let Ok(t) = subscription.parse::<i32>() {
-        if t > -1 && t < 3 {
-            return None;
-        }
+    match subscription {
+        "0" | "1" | "2" => None,
+        _ => Some("Invalid subscription policy!"),
     }
-    Some("Invalid subscription policy!")
 }
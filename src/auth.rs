@@ -0,0 +1,163 @@
+use serde_json::Value;
+
+use std::net::IpAddr;
+
+use crate::db::*;
+use crate::error::ApiError;
+use crate::permissions::{self, Permission};
+use crate::router::Role;
+use crate::session_store::Session;
+use crate::sessions;
+
+/// Where a request came from, as listed by `/list_sessions`.
+#[derive(Clone, Debug, Default)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    /// The `User-Agent` header.
+    pub device: String,
+}
+
+/// Who is making a request, resolved once from the `session` field of the body.
+pub struct AuthContext {
+    pub session: Option<Session>,
+    pub user_id: Option<String>,
+    pub email: Option<String>,
+    pub role: Role,
+    pub verified: bool,
+    /// Read from the `admin` table on every request, so revocations apply at once.
+    pub permissions: Vec<Permission>,
+    pub client: Client,
+}
+
+impl AuthContext {
+    pub fn anonymous() -> Self {
+        AuthContext {
+            session: None,
+            user_id: None,
+            email: None,
+            role: Role::Public,
+            verified: false,
+            permissions: Vec::new(),
+            client: Client::default(),
+        }
+    }
+
+    /// Resolves the caller and records that their session was used by `client`.
+    pub async fn resolve(body: &Value, client: Client) -> Self {
+        let mut auth = AuthContext::from_body(body).await;
+        if auth.verified {
+            if let Some(session) = &mut auth.session {
+                sessions::touch(session, &client).await;
+            }
+        }
+        auth.client = client;
+        auth
+    }
+
+    async fn from_body(body: &Value) -> Self {
+        let id = match body.get("session").and_then(Value::as_str) {
+            Some(id) => id,
+            None => return AuthContext::anonymous(),
+        };
+        let mut session = match Session::from_id(id).await {
+            Some(session) => session,
+            None => return AuthContext::anonymous(),
+        };
+        let admin = session.get("admin").await.unwrap_or_default() == "1";
+        let verified = admin || session.get("verified").await.unwrap_or_default() == "1";
+        let role = if admin {
+            Role::Admin
+        } else if verified {
+            Role::User
+        } else {
+            Role::Public
+        };
+        let user_id = session.get("id").await;
+        let mut email = session.get("email").await;
+        let mut permissions = Vec::new();
+        if verified {
+            // The account is read back on every request, so edits to it apply at once.
+            let table = if admin { "admin" } else { "users" };
+            let row = match &user_id {
+                Some(id) => get_like(table, "id", id).await.into_iter().next(),
+                None => None,
+            };
+            match row {
+                Some(row) => {
+                    email = Some(from_value(row[0].clone()));
+                    if admin {
+                        permissions =
+                            permissions::from_column(&from_value::<String>(row[5].clone()));
+                    }
+                }
+                // The account was deleted, or moved out of the table it logged in to.
+                None => {
+                    sessions::end(session).await;
+                    return AuthContext::anonymous();
+                }
+            }
+        }
+        AuthContext {
+            user_id,
+            email,
+            session: Some(session),
+            role,
+            verified,
+            permissions,
+            client: Client::default(),
+        }
+    }
+
+    /// Rejects callers without `required`: 401 if they are not logged in, 403 otherwise.
+    pub fn authorize(&self, required: Role) -> Result<(), ApiError> {
+        if self.role >= required {
+            Ok(())
+        } else if self.role == Role::Public {
+            Err(ApiError::SessionMissing)
+        } else {
+            Err(ApiError::NotAuthorized(
+                "This action requires an administrator account.".to_string(),
+            ))
+        }
+    }
+
+    /// Rejects admins without `permission`. `admin:manage` grants everything.
+    pub fn require(&self, permission: Permission) -> Result<(), ApiError> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            Err(ApiError::NotAuthorized(format!(
+                "This action requires the {} permission.",
+                permission
+            )))
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.is_admin()
+            && (self.permissions.contains(&permission)
+                || self.permissions.contains(&Permission::AdminManage))
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    pub fn session(&mut self) -> Result<&mut Session, ApiError> {
+        self.session.as_mut().ok_or(ApiError::SessionMissing)
+    }
+
+    pub fn user_id(&self) -> Result<&str, ApiError> {
+        self.user_id.as_deref().ok_or(ApiError::SessionMissing)
+    }
+
+    /// The key of the caller's account in the `account_sessions` table.
+    pub fn account(&self) -> Result<String, ApiError> {
+        let id = self.user_id()?;
+        Ok(if self.is_admin() {
+            sessions::admin_account(id)
+        } else {
+            sessions::user_account(id)
+        })
+    }
+}
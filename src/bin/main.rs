@@ -1,37 +1,391 @@
+use hyper::header::{HeaderMap, HeaderValue, ALLOW, CONTENT_TYPE, RETRY_AFTER, USER_AGENT};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use hyper::{Method, StatusCode};
+use olmmcc::auth::Client;
+use olmmcc::db::{self, MysqlRepository, Repository};
+use olmmcc::rate_limit::RateLimiter;
+use olmmcc::router::RateLimit;
+use olmmcc::{
+    cookies, cors, health, logging, metrics, session_store, ApiError, AuthContext, Config, Router,
+};
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::net::SocketAddr;
-
-async fn handle_request(request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    let mut response = Response::new(Body::empty());
-
-    match request.method() {
-        &Method::POST => {
-            let url = request.uri().to_string();
-            let request_vector = &hyper::body::to_bytes(request.into_body()).await?.to_vec();
-            let request_body = std::str::from_utf8(request_vector).unwrap();
-            if let Ok::<HashMap<&str, String>, _>(string_body_hash) =
-                serde_json::from_str(request_body)
-            {
-                let body_hash = string_body_hash
-                    .iter()
-                    .map(|(k, v)| (*k, v.as_str()))
-                    .collect();
-                let response_body = olmmcc::formulate_response(&url, body_hash).await;
-                *response.body_mut() = Body::from(response_body);
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{field, Instrument};
+
+const X_REQUEST_ID: &str = "x-request-id";
+const METRICS_PATH: &str = "/metrics";
+const HEALTHZ_PATH: &str = "/healthz";
+const READYZ_PATH: &str = "/readyz";
+
+/// Everything shared between requests.
+struct App {
+    config: Config,
+    router: Router,
+    /// The database every request and the session sweep query.
+    repository: Arc<dyn Repository>,
+    in_flight: InFlight,
+    limiter: RateLimiter,
+}
+
+/// The requests currently being handled, so shutdown can report what it cut off.
+#[derive(Default)]
+struct InFlight {
+    requests: Mutex<HashMap<String, (&'static str, Instant)>>,
+}
+
+/// Removes its request from [`InFlight`] when the request finishes or is dropped.
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    id: String,
+}
+
+impl InFlight {
+    fn start(&self, id: &str, route: &'static str) -> InFlightGuard<'_> {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.insert(id.to_string(), (route, Instant::now()));
+        }
+        InFlightGuard {
+            in_flight: self,
+            id: id.to_string(),
+        }
+    }
+
+    /// e.g. `2 requests: /signup (id k3J9, 31s), /get_songs (id 8xQ1, 30s)`.
+    fn summary(&self) -> String {
+        let requests = match self.requests.lock() {
+            Ok(t) => t,
+            Err(_) => return "unknown".to_string(),
+        };
+        let mut list: Vec<String> = requests
+            .iter()
+            .map(|(id, (route, start))| {
+                format!("{} (id {}, {}s)", route, id, start.elapsed().as_secs())
+            })
+            .collect();
+        list.sort();
+        format!("{} requests: {}", list.len(), list.join(", "))
+    }
+
+    fn len(&self) -> usize {
+        self.requests.lock().map(|t| t.len()).unwrap_or_default()
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut requests) = self.in_flight.requests.lock() {
+            requests.remove(&self.id);
+        }
+    }
+}
+
+fn status_code(error: &ApiError) -> StatusCode {
+    match error {
+        ApiError::SessionMissing | ApiError::TotpRequired { .. } => StatusCode::UNAUTHORIZED,
+        ApiError::NotAuthorized(_) | ApiError::CsrfMismatch => StatusCode::FORBIDDEN,
+        ApiError::Database(_) | ApiError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ApiError::Mail(_) => StatusCode::BAD_GATEWAY,
+        ApiError::Validation(_) | ApiError::Code(_) => StatusCode::BAD_REQUEST,
+        ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+        ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+    }
+}
+
+fn api_error_response(error: &ApiError) -> Response<Body> {
+    let mut response = json_response(status_code(error), error.body());
+    if let ApiError::RateLimited { retry_after } = error {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
+    }
+    response
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error_response(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    json_response(
+        status,
+        json!({ "error": { "code": code, "message": message } }).to_string(),
+    )
+}
+
+fn content_type(request: &Request<Body>) -> Option<String> {
+    let header = request.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+    Some(header.split(';').next()?.trim().to_lowercase())
+}
+
+/// Turns a query string or form body into an object of string fields.
+fn decode_form(form: &[u8]) -> Option<Value> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_bytes(form).ok()?;
+    Some(Value::Object(
+        pairs
+            .into_iter()
+            .map(|(k, v)| (k, Value::String(v)))
+            .collect::<Map<String, Value>>(),
+    ))
+}
+
+async fn read_body(request: Request<Body>) -> Result<Result<Value, Response<Body>>, hyper::Error> {
+    if request.method() == Method::GET {
+        let query = request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec();
+        return Ok(decode_form(&query).ok_or_else(|| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "validation",
+                "The query string could not be decoded.",
+            )
+        }));
+    }
+    let content_type = content_type(&request);
+    let bytes = hyper::body::to_bytes(request.into_body()).await?;
+    let body = match content_type.as_deref() {
+        Some("application/x-www-form-urlencoded") => decode_form(&bytes),
+        // The frontend posts JSON strings with fetch's default text/plain content type.
+        Some("application/json") | Some("text/plain") | None => {
+            if bytes.is_empty() {
+                Some(json!({}))
             } else {
-                *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
-                *response.body_mut() = Body::from(
-                    "The OLMMCC api only supports application/x-www-form-urlencoded.".to_string(),
-                );
+                serde_json::from_slice(&bytes).ok()
             }
         }
-        _ => {
-            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
-            *response.body_mut() = Body::from("The OLMMCC api only supports POST.".to_string());
+        Some(other) => {
+            return Ok(Err(error_response(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                &format!(
+                    "The content type {} is not supported. Please use application/json or application/x-www-form-urlencoded.",
+                    other
+                ),
+            )))
+        }
+    };
+    Ok(body.ok_or_else(|| {
+        error_response(
+            StatusCode::BAD_REQUEST,
+            "validation",
+            "The request body could not be decoded.",
+        )
+    }))
+}
+
+fn metrics_response() -> Response<Body> {
+    let mut response = Response::new(Body::from(metrics::render()));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response
+}
+
+/// Probes are limited like ordinary routes, as each one writes a session.
+async fn readiness_response(app: &App, ip: IpAddr) -> Response<Body> {
+    let config = &app.config;
+    if let Err(e) = app
+        .limiter
+        .check(&config.rate_limit, RateLimit::Standard, ip)
+    {
+        return api_error_response(&e);
+    }
+    let readiness = health::readiness(config).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(status, json!(readiness).to_string())
+}
+
+/// Reuses the caller's `X-Request-Id` if it is short and printable, so requests can be
+/// followed through a proxy.
+fn request_id(request: &Request<Body>) -> String {
+    match request.headers().get(X_REQUEST_ID).map(HeaderValue::to_str) {
+        Some(Ok(t)) if !t.is_empty() && t.len() <= 64 => t.to_string(),
+        _ => logging::request_id(),
+    }
+}
+
+/// The caller's address, looking through `X-Forwarded-For` if the peer is a trusted proxy.
+fn client_ip(app: &App, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    if !app.config.rate_limit.trusted_proxies.contains(&peer.ip()) {
+        return peer.ip();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|t| t.to_str().ok())
+        .flat_map(|t| t.split(','))
+        .filter_map(|t| t.trim().parse().ok())
+        .next_back()
+        .unwrap_or_else(|| peer.ip())
+}
+
+async fn handle_request(
+    app: Arc<App>,
+    peer: SocketAddr,
+    request: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = request_id(&request);
+    let ip = client_ip(&app, peer, request.headers());
+    let span = tracing::info_span!(
+        "request",
+        id = %id,
+        ip = %ip,
+        method = %request.method(),
+        route = %request.uri().path(),
+        user_id = field::Empty,
+    );
+    // Unknown paths share one label so scanners cannot blow up the metric cardinality.
+    let route = match request.uri().path() {
+        METRICS_PATH => METRICS_PATH,
+        HEALTHZ_PATH => HEALTHZ_PATH,
+        READYZ_PATH => READYZ_PATH,
+        path => app.router.find(path).map_or("unmatched", |t| t.path),
+    };
+    let repository = app.repository.clone();
+    let response = async move {
+        let _guard = app.in_flight.start(&id, route);
+        let start = Instant::now();
+        let mut response = if request.method() == Method::OPTIONS {
+            cors::preflight(&app.config.cors, &request)
+        } else if route == METRICS_PATH {
+            metrics_response()
+        } else if route == HEALTHZ_PATH {
+            json_response(StatusCode::OK, json!({ "alive": true }).to_string())
+        } else if route == READYZ_PATH {
+            readiness_response(&app, ip).await
+        } else {
+            let origin = cors::allowed_origin(&app.config.cors, request.headers());
+            let mut response = route_request(&app, ip, request).await?;
+            cors::add_headers(&app.config.cors, origin, &mut response);
+            response
+        };
+        if let Ok(t) = HeaderValue::from_str(&id) {
+            response.headers_mut().insert(X_REQUEST_ID, t);
+        }
+        let elapsed = start.elapsed();
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[route, response.status().as_str()])
+            .inc();
+        metrics::HTTP_REQUEST_SECONDS
+            .with_label_values(&[route])
+            .observe(elapsed.as_secs_f64());
+        tracing::info!(
+            status = response.status().as_u16(),
+            latency_ms = elapsed.as_millis() as u64,
+            "request completed"
+        );
+        Ok(response)
+    }
+    .instrument(span);
+    db::scope(repository, response).await
+}
+
+async fn route_request(
+    app: &App,
+    ip: IpAddr,
+    request: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let path = request.uri().path().to_string();
+    let route = match app.router.find(&path) {
+        Some(route) => route,
+        None => {
+            let e = ApiError::NotFound(format!("The provided url {} could not be resolved.", path));
+            return Ok(api_error_response(&e));
+        }
+    };
+    if !route.allows(request.method()) {
+        let allowed = route.allow_header();
+        let mut response = error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            &format!("{} only supports {}.", route.path, allowed),
+        );
+        if let Ok(t) = HeaderValue::from_str(&allowed) {
+            response.headers_mut().insert(ALLOW, t);
+        }
+        return Ok(response);
+    }
+    let client = Client {
+        ip: Some(ip),
+        device: request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|t| t.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+    };
+    let cookie_session = cookies::from_headers(&app.config.session_cookie, request.headers());
+    let may_issue = cookies::may_issue(request.headers());
+    let method = request.method().clone();
+    let mut body = match read_body(request).await? {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let attached = match &cookie_session {
+        Some(t) => t.attach(&app.config, &method, route.role, &mut body),
+        None => Ok(()),
+    };
+    tracing::debug!(body = %logging::redact(&body), "request body");
+    let auth = AuthContext::resolve(&body, client).await;
+    if let Some(id) = &auth.user_id {
+        tracing::Span::current().record("user_id", &id.as_str());
+    }
+    // Mail routes are also limited per recipient, by the handlers once they know the address.
+    let result = match attached.and_then(|()| {
+        app.limiter
+            .check(&app.config.rate_limit, route.rate_limit, ip)
+    }) {
+        Ok(()) => route.call(&app.config, auth, &body).await,
+        Err(e) => Err(e),
+    };
+    let cookie_config = &app.config.session_cookie;
+    let issued = match &result {
+        _ if !cookie_config.enabled || !may_issue => None,
+        Ok(response_body) => cookies::issued_session(response_body),
+        Err(ApiError::TotpRequired { session }) => Some(session.clone()),
+        Err(_) => None,
+    };
+    let result = match issued {
+        Some(_) if !cookie_config.session_in_body => cookies::without_session(result),
+        _ => result,
+    };
+    let mut response = match result {
+        Ok(response_body) => json_response(StatusCode::OK, response_body),
+        Err(e) => {
+            if status_code(&e).is_server_error() {
+                tracing::error!(outcome = e.code(), error = %e.message(), "request failed");
+            } else {
+                tracing::info!(outcome = e.code(), "request rejected");
+            }
+            api_error_response(&e)
+        }
+    };
+    if cookie_config.enabled {
+        match (issued, &cookie_session) {
+            (Some(id), _) => cookies::issue(&app.config, &id, &mut response),
+            (None, Some(t)) if t.is_gone().await => cookies::clear(cookie_config, &mut response),
+            _ => {}
         }
     }
     Ok(response)
@@ -39,14 +393,109 @@ async fn handle_request(request: Request<Body>) -> Result<Response<Body>, hyper:
 
 #[tokio::main]
 async fn main() {
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let router = olmmcc::router();
+    if env::args().any(|arg| arg == "--routes") {
+        for route in router.routes() {
+            println!("{}", route);
+        }
+        return;
+    }
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init(&config.log);
+    session_store::init(&config);
+    let addr = config.bind_address;
+    let drain_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let sweep_interval = Duration::from_secs(config.session_sweep_minutes * 60);
+    let repository: Arc<dyn Repository> = Arc::new(MysqlRepository);
+    tokio::spawn(db::scope(repository.clone(), async move {
+        let mut interval = tokio::time::interval(sweep_interval);
+        loop {
+            interval.tick().await;
+            let swept = session_store::sweep().await;
+            if swept > 0 {
+                tracing::info!(swept, "removed expired sessions");
+            }
+        }
+    }));
+    let app = Arc::new(App {
+        config,
+        router,
+        repository,
+        in_flight: InFlight::default(),
+        limiter: RateLimiter::new(),
+    });
 
-    let make_svc =
-        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+    let service_app = app.clone();
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let app = service_app.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(app.clone(), peer, request)
+            }))
+        }
+    });
 
-    let server = Server::bind(&addr).serve(make_svc);
+    let (signalled, on_signal) = oneshot::channel();
+    let server = Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            shutdown_signal().await;
+            let _ = signalled.send(());
+        });
+    tokio::pin!(server);
+    tracing::info!(address = %addr, "listening");
 
-    if let Err(e) = server.await {
-        eprintln!("Server error: {}", e);
+    let result = tokio::select! {
+        result = &mut server => result,
+        _ = on_signal => {
+            tracing::info!(
+                in_flight = app.in_flight.len(),
+                timeout_secs = drain_timeout.as_secs(),
+                "shutting down, waiting for in-flight requests"
+            );
+            match tokio::time::timeout(drain_timeout, &mut server).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tracing::warn!(
+                        cut_off = %app.in_flight.summary(),
+                        "drain timeout reached, exiting with requests still in flight"
+                    );
+                    return;
+                }
+            }
+        }
+    };
+    match result {
+        Ok(()) => tracing::info!("shut down cleanly"),
+        Err(e) => tracing::error!(error = %e, "server error"),
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::warn!(error = %e, "could not listen for SIGTERM"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!(error = %e, "could not listen for SIGINT");
+        std::future::pending::<()>().await;
     }
 }
@@ -0,0 +1,33 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keys the hash to the session and purpose, so a stored hash cannot be replayed elsewhere.
+fn mac(secret: &str, session_id: &str, purpose: &str, code: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC accepts any key length");
+    for part in &[session_id, purpose, code] {
+        mac.update(part.as_bytes());
+        mac.update(&[0]);
+    }
+    mac
+}
+
+/// The hex encoded keyed hash of a code, which is what the session stores.
+pub fn hash(secret: &str, session_id: &str, purpose: &str, code: &str) -> String {
+    hex::encode(
+        mac(secret, session_id, purpose, code)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Checks a submitted code against a stored hash in constant time.
+pub fn matches(secret: &str, session_id: &str, purpose: &str, code: &str, stored: &str) -> bool {
+    match hex::decode(stored) {
+        Ok(stored) => mac(secret, session_id, purpose, code)
+            .verify(&stored)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,496 @@
+use serde::Deserialize;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::session_store::StoreKind;
+
+/// The file read when `OLMMCC_CONFIG` is not set. It is optional.
+const DEFAULT_CONFIG_FILE: &str = "olmmcc.toml";
+
+/// Every deployment-specific setting of the server.
+///
+/// Values are read from a TOML file and can then be overridden by `OLMMCC_*` environment
+/// variables, e.g. `OLMMCC_BIND_ADDRESS=0.0.0.0:3000`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    pub image_directory: PathBuf,
+    pub gmail_client_secret: PathBuf,
+    pub gmail_redirect_uri: String,
+    pub contact_email: String,
+    pub session_lifetime_days: u64,
+    pub session_id_length: u64,
+    pub session_store: StoreKind,
+    /// How often expired sessions are removed from the store.
+    pub session_sweep_minutes: u64,
+    /// How long an emailed verification code can be used for.
+    pub code_ttl_minutes: u64,
+    /// The page login emails link to, with `?token=...` appended. Empty disables links.
+    pub login_link_url: String,
+    /// The key verification codes, login links and CSRF tokens are hashed with. It may
+    /// only be left empty with the memory session store, which gets a random one.
+    pub code_secret: String,
+    /// How long to wait for in-flight requests after SIGTERM or SIGINT.
+    pub shutdown_timeout_secs: u64,
+    pub cors: CorsConfig,
+    pub session_cookie: SessionCookieConfig,
+    pub log: LogConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+/// Which cross-origin callers may use the api, set in the `[cors]` table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to read responses. `"*"` allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// How many seconds browsers may cache a preflight response.
+    pub max_age: u64,
+    /// Lets allowed origins send cookies, which a cross-origin `[session_cookie]` needs.
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec![
+                "https://www.olmmcc.tk".to_string(),
+                "https://olmmcc.tk".to_string(),
+            ],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "OPTIONS".to_string()],
+            allowed_headers: vec!["Content-Type".to_string(), "X-CSRF-Token".to_string()],
+            max_age: 86400,
+            allow_credentials: false,
+        }
+    }
+}
+
+/// Whether the session id is also sent in an `HttpOnly` cookie, set in the
+/// `[session_cookie]` table. A `session` field in the body is still accepted.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionCookieConfig {
+    pub enabled: bool,
+    pub name: String,
+    /// The cookie scripts read the CSRF token from, to echo it in `X-CSRF-Token`.
+    pub csrf_name: String,
+    /// Empty for cookies which only go to the api's own host.
+    pub domain: String,
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Whether responses which set the cookie also return the session id in their body.
+    pub session_in_body: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl FromStr for SameSite {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Strict" => Ok(SameSite::Strict),
+            "Lax" => Ok(SameSite::Lax),
+            "None" => Ok(SameSite::None),
+            _ => Err("expected Strict, Lax or None".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Default for SessionCookieConfig {
+    fn default() -> Self {
+        SessionCookieConfig {
+            enabled: false,
+            name: "olmmcc_session".to_string(),
+            csrf_name: "olmmcc_csrf".to_string(),
+            domain: String::new(),
+            secure: true,
+            same_site: SameSite::Strict,
+            session_in_body: true,
+        }
+    }
+}
+
+/// Limits on how often one caller may use the api, set in the `[rate_limit]` table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Per client address, for ordinary routes.
+    pub requests_per_minute: u32,
+    /// Per client address, for routes which check a code or password.
+    pub codes_per_minute: u32,
+    /// Per client address, for routes which send an email.
+    pub emails_per_ip_per_hour: u32,
+    /// Per recipient, for routes which send an email.
+    pub emails_per_address_per_hour: u32,
+    /// Wrong guesses allowed before an emailed code stops working, or before an admin's
+    /// authenticator codes are refused for a while.
+    pub max_code_attempts: u32,
+    /// Peers whose `X-Forwarded-For` header names the real client, e.g. a local nginx.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_minute: 120,
+            codes_per_minute: 10,
+            emails_per_ip_per_hour: 10,
+            emails_per_address_per_hour: 3,
+            max_code_attempts: 5,
+            trusted_proxies: vec![
+                IpAddr::from([127, 0, 0, 1]),
+                IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+            ],
+        }
+    }
+}
+
+/// How the server logs, set in the `[log]` table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// A `tracing` filter such as `info` or `olmmcc=debug,hyper=warn`.
+    pub level: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            image_directory: PathBuf::from("/srv/http/images/"),
+            gmail_client_secret: PathBuf::from("/home/justus/client_secret.json"),
+            gmail_redirect_uri: "https://www.olmmcc.tk/admin/email/".to_string(),
+            contact_email: "justus@olmmcc.tk".to_string(),
+            session_lifetime_days: 30,
+            session_id_length: 100,
+            session_store: StoreKind::Mysql,
+            session_sweep_minutes: 60,
+            code_ttl_minutes: 15,
+            login_link_url: String::new(),
+            code_secret: String::new(),
+            shutdown_timeout_secs: 30,
+            cors: CorsConfig::default(),
+            session_cookie: SessionCookieConfig::default(),
+            log: LogConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(&'static str, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Env(var, e) => write!(f, "invalid value for {}: {}", var, e),
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn override_from_env<T: FromStr>(var: &'static str, value: &mut T) -> Result<(), ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    if let Ok(t) = env::var(var) {
+        *value = t
+            .parse()
+            .map_err(|e: T::Err| ConfigError::Env(var, e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Reads a comma separated list, e.g. `OLMMCC_CORS_ALLOWED_ORIGINS=https://a.tk,https://b.tk`.
+fn override_list_from_env<T: FromStr>(
+    var: &'static str,
+    value: &mut Vec<T>,
+) -> Result<(), ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    if let Ok(t) = env::var(var) {
+        *value = t
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(|t| t.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|e: T::Err| ConfigError::Env(var, e.to_string()))?;
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Loads the file named by `OLMMCC_CONFIG` (or `olmmcc.toml` if it exists), applies
+    /// environment overrides and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var("OLMMCC_CONFIG") {
+            Ok(path) => Config::from_file(PathBuf::from(path))?,
+            Err(_) if PathBuf::from(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(PathBuf::from(DEFAULT_CONFIG_FILE))?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        // Sessions in memory die with the process, and so may everything hashed for them.
+        if config.code_secret.is_empty() && config.session_store == StoreKind::Memory {
+            config.code_secret = crate::generate_token(64);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_from_env("OLMMCC_BIND_ADDRESS", &mut self.bind_address)?;
+        override_from_env("OLMMCC_IMAGE_DIRECTORY", &mut self.image_directory)?;
+        override_from_env("OLMMCC_GMAIL_CLIENT_SECRET", &mut self.gmail_client_secret)?;
+        override_from_env("OLMMCC_GMAIL_REDIRECT_URI", &mut self.gmail_redirect_uri)?;
+        override_from_env("OLMMCC_CONTACT_EMAIL", &mut self.contact_email)?;
+        override_from_env(
+            "OLMMCC_SESSION_LIFETIME_DAYS",
+            &mut self.session_lifetime_days,
+        )?;
+        override_from_env("OLMMCC_SESSION_ID_LENGTH", &mut self.session_id_length)?;
+        override_from_env("OLMMCC_SESSION_STORE", &mut self.session_store)?;
+        override_from_env(
+            "OLMMCC_SESSION_SWEEP_MINUTES",
+            &mut self.session_sweep_minutes,
+        )?;
+        override_from_env("OLMMCC_CODE_TTL_MINUTES", &mut self.code_ttl_minutes)?;
+        override_from_env("OLMMCC_LOGIN_LINK_URL", &mut self.login_link_url)?;
+        override_from_env("OLMMCC_CODE_SECRET", &mut self.code_secret)?;
+        override_from_env(
+            "OLMMCC_SHUTDOWN_TIMEOUT_SECS",
+            &mut self.shutdown_timeout_secs,
+        )?;
+        override_list_from_env(
+            "OLMMCC_CORS_ALLOWED_ORIGINS",
+            &mut self.cors.allowed_origins,
+        )?;
+        override_list_from_env(
+            "OLMMCC_CORS_ALLOWED_METHODS",
+            &mut self.cors.allowed_methods,
+        )?;
+        override_list_from_env(
+            "OLMMCC_CORS_ALLOWED_HEADERS",
+            &mut self.cors.allowed_headers,
+        )?;
+        override_from_env("OLMMCC_CORS_MAX_AGE", &mut self.cors.max_age)?;
+        override_from_env(
+            "OLMMCC_CORS_ALLOW_CREDENTIALS",
+            &mut self.cors.allow_credentials,
+        )?;
+        let cookie = &mut self.session_cookie;
+        override_from_env("OLMMCC_SESSION_COOKIE_ENABLED", &mut cookie.enabled)?;
+        override_from_env("OLMMCC_SESSION_COOKIE_NAME", &mut cookie.name)?;
+        override_from_env("OLMMCC_SESSION_COOKIE_CSRF_NAME", &mut cookie.csrf_name)?;
+        override_from_env("OLMMCC_SESSION_COOKIE_DOMAIN", &mut cookie.domain)?;
+        override_from_env("OLMMCC_SESSION_COOKIE_SECURE", &mut cookie.secure)?;
+        override_from_env("OLMMCC_SESSION_COOKIE_SAME_SITE", &mut cookie.same_site)?;
+        override_from_env(
+            "OLMMCC_SESSION_COOKIE_SESSION_IN_BODY",
+            &mut cookie.session_in_body,
+        )?;
+        override_from_env("OLMMCC_LOG_FORMAT", &mut self.log.format)?;
+        override_from_env("OLMMCC_LOG_LEVEL", &mut self.log.level)?;
+        let limits = &mut self.rate_limit;
+        override_from_env(
+            "OLMMCC_RATE_LIMIT_REQUESTS_PER_MINUTE",
+            &mut limits.requests_per_minute,
+        )?;
+        override_from_env(
+            "OLMMCC_RATE_LIMIT_CODES_PER_MINUTE",
+            &mut limits.codes_per_minute,
+        )?;
+        override_from_env(
+            "OLMMCC_RATE_LIMIT_EMAILS_PER_IP_PER_HOUR",
+            &mut limits.emails_per_ip_per_hour,
+        )?;
+        override_from_env(
+            "OLMMCC_RATE_LIMIT_EMAILS_PER_ADDRESS_PER_HOUR",
+            &mut limits.emails_per_address_per_hour,
+        )?;
+        override_from_env(
+            "OLMMCC_RATE_LIMIT_MAX_CODE_ATTEMPTS",
+            &mut limits.max_code_attempts,
+        )?;
+        override_list_from_env(
+            "OLMMCC_RATE_LIMIT_TRUSTED_PROXIES",
+            &mut limits.trusted_proxies,
+        )?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.image_directory.is_dir() {
+            return Err(ConfigError::Invalid(format!(
+                "image_directory {} is not a directory",
+                self.image_directory.display()
+            )));
+        }
+        if !self.gmail_client_secret.is_file() {
+            return Err(ConfigError::Invalid(format!(
+                "gmail_client_secret {} is not a file",
+                self.gmail_client_secret.display()
+            )));
+        }
+        if !self.gmail_redirect_uri.starts_with("https://")
+            && !self.gmail_redirect_uri.starts_with("http://")
+        {
+            return Err(ConfigError::Invalid(
+                "gmail_redirect_uri must be an http(s) url".to_string(),
+            ));
+        }
+        if !self.login_link_url.is_empty()
+            && !self.login_link_url.starts_with("https://")
+            && !self.login_link_url.starts_with("http://")
+        {
+            return Err(ConfigError::Invalid(
+                "login_link_url must be empty or an http(s) url".to_string(),
+            ));
+        }
+        if !self.contact_email.contains('@') {
+            return Err(ConfigError::Invalid(
+                "contact_email must be an email address".to_string(),
+            ));
+        }
+        if self.session_lifetime_days == 0
+            || self.session_id_length == 0
+            || self.session_sweep_minutes == 0
+        {
+            return Err(ConfigError::Invalid(
+                "session_lifetime_days, session_id_length and session_sweep_minutes must be positive"
+                    .to_string(),
+            ));
+        }
+        if self.code_secret.is_empty() {
+            return Err(ConfigError::Invalid(
+                "code_secret must be set unless session_store = \"memory\"".to_string(),
+            ));
+        }
+        if self.code_ttl_minutes == 0 {
+            return Err(ConfigError::Invalid(
+                "code_ttl_minutes must be positive".to_string(),
+            ));
+        }
+        for method in &self.cors.allowed_methods {
+            if hyper::Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "cors.allowed_methods contains an invalid method {}",
+                    method
+                )));
+            }
+        }
+        for header in &self.cors.allowed_headers {
+            if hyper::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "cors.allowed_headers contains an invalid header {}",
+                    header
+                )));
+            }
+        }
+        if self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|t| t == "*") {
+            return Err(ConfigError::Invalid(
+                "cors.allow_credentials needs explicit cors.allowed_origins, not *".to_string(),
+            ));
+        }
+        let cookie = &self.session_cookie;
+        for name in &[&cookie.name, &cookie.csrf_name] {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|t| t.is_ascii_alphanumeric() || t == '_' || t == '-')
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "session_cookie name {:?} may only contain letters, digits, _ and -",
+                    name
+                )));
+            }
+        }
+        if cookie.same_site == SameSite::None && !cookie.secure {
+            return Err(ConfigError::Invalid(
+                "session_cookie.same_site = \"None\" needs secure = true".to_string(),
+            ));
+        }
+        let limits = &self.rate_limit;
+        if limits.requests_per_minute == 0
+            || limits.codes_per_minute == 0
+            || limits.emails_per_ip_per_hour == 0
+            || limits.emails_per_address_per_hour == 0
+            || limits.max_code_attempts == 0
+        {
+            return Err(ConfigError::Invalid(
+                "every rate_limit value must be positive".to_string(),
+            ));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            return Err(ConfigError::Invalid(format!("log.level is invalid: {}", e)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE};
+use hyper::{Body, Method, Response};
+use serde_json::Value;
+
+use crate::codes;
+use crate::config::{Config, SessionCookieConfig};
+use crate::error::ApiError;
+use crate::router::Role;
+use crate::session_store::Session;
+
+/// The header a script copies the CSRF cookie into on every POST.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// CSRF tokens are keyed hashes of the session id, so they need not be stored.
+const CSRF_PURPOSE: &str = "csrf_token";
+
+/// A session id read from the request's cookie, with the CSRF header sent alongside it.
+pub struct CookieSession {
+    pub id: String,
+    csrf_token: Option<String>,
+}
+
+/// Reads the session cookie, if cookies are enabled and the browser sent one.
+pub fn from_headers(config: &SessionCookieConfig, headers: &HeaderMap) -> Option<CookieSession> {
+    if !config.enabled {
+        return None;
+    }
+    let id = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|t| t.to_str().ok())
+        .flat_map(|t| t.split(';'))
+        .filter_map(|t| {
+            let mut pair = t.trim().splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(name), Some(value)) if name == config.name && !value.is_empty() => {
+                    Some(value.to_string())
+                }
+                _ => None,
+            }
+        })
+        .next()?;
+    Some(CookieSession {
+        id,
+        csrf_token: headers
+            .get(CSRF_HEADER)
+            .and_then(|t| t.to_str().ok())
+            .map(str::to_string),
+    })
+}
+
+impl CookieSession {
+    /// Copies the session id into the body, unless the body names a session itself.
+    ///
+    /// POSTs must echo the session's CSRF token. Without it the cookie is ignored on
+    /// public routes, so a stale cookie never blocks logging in, and refused elsewhere.
+    pub fn attach(
+        &self,
+        config: &Config,
+        method: &Method,
+        role: Role,
+        body: &mut Value,
+    ) -> Result<(), ApiError> {
+        let map = match body {
+            Value::Object(map) if !map.contains_key("session") => map,
+            _ => return Ok(()),
+        };
+        if method == Method::POST && !self.csrf_matches(config) {
+            return if role == Role::Public {
+                Ok(())
+            } else {
+                Err(ApiError::CsrfMismatch)
+            };
+        }
+        map.insert("session".to_string(), Value::String(self.id.clone()));
+        Ok(())
+    }
+
+    fn csrf_matches(&self, config: &Config) -> bool {
+        match &self.csrf_token {
+            Some(t) => codes::matches(&config.code_secret, &self.id, CSRF_PURPOSE, "", t),
+            None => false,
+        }
+    }
+
+    /// Whether the session was logged out or expired while the request was handled.
+    pub async fn is_gone(&self) -> bool {
+        Session::from_id(&self.id).await.is_none()
+    }
+}
+
+/// Whether a response may set the session cookie. The request must carry the
+/// `X-CSRF-Token` header, with any value, which a cross-site form cannot send, so
+/// another site cannot log the browser into an account of its choosing.
+pub fn may_issue(headers: &HeaderMap) -> bool {
+    headers.contains_key(CSRF_HEADER)
+}
+
+/// Removes the session id from a response whose session was only set in the cookie.
+pub fn without_session(result: Result<String, ApiError>) -> Result<String, ApiError> {
+    match result {
+        Ok(body) => match serde_json::from_str::<Value>(&body) {
+            Ok(Value::Object(mut map)) => {
+                map.remove("session");
+                Ok(Value::Object(map).to_string())
+            }
+            _ => Ok(body),
+        },
+        Err(ApiError::TotpRequired { .. }) => Err(ApiError::TotpRequired {
+            session: String::new(),
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+/// The session id a response hands to the client, in its body or in a `totp_required` error.
+pub fn issued_session(body: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(body).ok()?;
+    body.get("session")
+        .or_else(|| body.get("error")?.get("session"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+fn cookie(
+    config: &SessionCookieConfig,
+    name: &str,
+    value: &str,
+    max_age: u64,
+    http_only: bool,
+) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/; Max-Age={}; SameSite={}",
+        name, value, max_age, config.same_site
+    );
+    if !config.domain.is_empty() {
+        cookie.push_str(&format!("; Domain={}", config.domain));
+    }
+    if config.secure {
+        cookie.push_str("; Secure");
+    }
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    cookie
+}
+
+fn append(response: &mut Response<Body>, cookie: String) {
+    if let Ok(t) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().append(SET_COOKIE, t);
+    }
+}
+
+/// Sets the session cookie and the readable CSRF cookie for a session the response issued.
+pub fn issue(config: &Config, session_id: &str, response: &mut Response<Body>) {
+    let csrf_token = codes::hash(&config.code_secret, session_id, CSRF_PURPOSE, "");
+    let cookies = &config.session_cookie;
+    let max_age = config.session_lifetime_days * 24 * 60 * 60;
+    append(
+        response,
+        cookie(cookies, &cookies.name, session_id, max_age, true),
+    );
+    append(
+        response,
+        cookie(cookies, &cookies.csrf_name, &csrf_token, max_age, false),
+    );
+}
+
+/// Tells the browser to forget both cookies.
+pub fn clear(config: &SessionCookieConfig, response: &mut Response<Body>) {
+    append(response, cookie(config, &config.name, "", 0, true));
+    append(response, cookie(config, &config.csrf_name, "", 0, false));
+}
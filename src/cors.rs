@@ -0,0 +1,60 @@
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ORIGIN,
+    VARY,
+};
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::config::CorsConfig;
+
+/// Returns the request's `Origin` if it is allowed to read responses.
+pub fn allowed_origin(config: &CorsConfig, headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = headers.get(ORIGIN)?;
+    let allowed = config
+        .allowed_origins
+        .iter()
+        .any(|t| t == "*" || Some(t.as_str()) == origin.to_str().ok());
+    if allowed {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+/// Adds `Access-Control-Allow-Origin` to a response if the origin is allowed.
+pub fn add_headers(
+    config: &CorsConfig,
+    origin: Option<HeaderValue>,
+    response: &mut Response<Body>,
+) {
+    let headers = response.headers_mut();
+    headers.insert(VARY, HeaderValue::from_static("Origin"));
+    if let Some(origin) = origin {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if config.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+/// Answers an `OPTIONS` preflight request.
+pub fn preflight(config: &CorsConfig, request: &Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let origin = allowed_origin(config, request.headers());
+    if origin.is_some() {
+        let headers = response.headers_mut();
+        if let Ok(t) = HeaderValue::from_str(&config.allowed_methods.join(", ")) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, t);
+        }
+        if let Ok(t) = HeaderValue::from_str(&config.allowed_headers.join(", ")) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, t);
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(config.max_age));
+    }
+    add_headers(config, origin, &mut response);
+    response
+}
@@ -0,0 +1,242 @@
+pub use mysql::{from_value, MyValue, Value};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::metrics::DB_QUERY_SECONDS;
+
+pub type DbFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The table helpers every handler reads and writes through. Rows come back as mysql
+/// values whatever the backend, so `from_value` works on all of them.
+pub trait Repository: Send + Sync {
+    /// Rows where `column` is `LIKE` `value`.
+    fn get_like<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, Vec<Vec<Value>>>;
+    /// Rows where `column` equals `value`, in which `%` and `_` are not wildcards. Use it
+    /// for values a client sent, so they cannot match more rows than they name.
+    fn get_where<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, Vec<Vec<Value>>>;
+    /// Every row, ordered by `id` if `sorted`.
+    fn get_all_rows<'a>(&'a self, table: &'a str, sorted: bool) -> DbFuture<'a, Vec<Vec<Value>>>;
+    /// One column of every row.
+    fn get_some<'a>(&'a self, table: &'a str, column: &'a str) -> DbFuture<'a, Vec<Vec<Value>>>;
+    /// A row per column, starting with its name and its lower case type such as `int(11)`.
+    fn get_column_details<'a>(&'a self, table: &'a str) -> DbFuture<'a, Vec<Vec<Value>>>;
+    fn insert_row<'a>(
+        &'a self,
+        table: &'a str,
+        names: Vec<&'a str>,
+        values: Vec<&'a str>,
+    ) -> DbFuture<'a, Result<(), String>>;
+    fn change_row_where<'a>(
+        &'a self,
+        table: &'a str,
+        where_column: &'a str,
+        where_value: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, ()>;
+    fn delete_row_where<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, ()>;
+    fn get_max_id<'a>(&'a self, table: &'a str) -> DbFuture<'a, i32>;
+    fn get_min_id<'a>(&'a self, table: &'a str) -> DbFuture<'a, i32>;
+    fn row_exists<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, bool>;
+}
+
+/// The production backend, the helpers of the `mysql` crate.
+pub struct MysqlRepository;
+
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for t in value.chars() {
+        if t == '\\' || t == '%' || t == '_' {
+            escaped.push('\\');
+        }
+        escaped.push(t);
+    }
+    escaped
+}
+
+impl Repository for MysqlRepository {
+    fn get_like<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, Vec<Vec<Value>>> {
+        Box::pin(mysql::get_like(table, column, value))
+    }
+    /// A `LIKE` with its wildcards escaped, as the `mysql` crate has no plain comparison.
+    fn get_where<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, Vec<Vec<Value>>> {
+        Box::pin(async move { mysql::get_like(table, column, &escape_like(value)).await })
+    }
+    fn get_all_rows<'a>(&'a self, table: &'a str, sorted: bool) -> DbFuture<'a, Vec<Vec<Value>>> {
+        Box::pin(mysql::get_all_rows(table, sorted))
+    }
+    fn get_some<'a>(&'a self, table: &'a str, column: &'a str) -> DbFuture<'a, Vec<Vec<Value>>> {
+        Box::pin(mysql::get_some(table, column))
+    }
+    fn get_column_details<'a>(&'a self, table: &'a str) -> DbFuture<'a, Vec<Vec<Value>>> {
+        Box::pin(mysql::get_column_details(table))
+    }
+    fn insert_row<'a>(
+        &'a self,
+        table: &'a str,
+        names: Vec<&'a str>,
+        values: Vec<&'a str>,
+    ) -> DbFuture<'a, Result<(), String>> {
+        Box::pin(mysql::insert_row(table, names, values))
+    }
+    fn change_row_where<'a>(
+        &'a self,
+        table: &'a str,
+        where_column: &'a str,
+        where_value: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, ()> {
+        Box::pin(mysql::change_row_where(
+            table,
+            where_column,
+            where_value,
+            column,
+            value,
+        ))
+    }
+    fn delete_row_where<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, ()> {
+        Box::pin(mysql::delete_row_where(table, column, value))
+    }
+    fn get_max_id<'a>(&'a self, table: &'a str) -> DbFuture<'a, i32> {
+        Box::pin(mysql::get_max_id(table))
+    }
+    fn get_min_id<'a>(&'a self, table: &'a str) -> DbFuture<'a, i32> {
+        Box::pin(mysql::get_min_id(table))
+    }
+    fn row_exists<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, bool> {
+        Box::pin(mysql::row_exists(table, column, value))
+    }
+}
+
+tokio::task_local! {
+    static REPOSITORY: Arc<dyn Repository>;
+}
+
+/// Runs `f` with every helper below querying `repository`. The server runs each request
+/// and its session sweep in a scope of the repository it was started with, and tests can
+/// give each test a database of its own.
+pub async fn scope<F: Future>(repository: Arc<dyn Repository>, f: F) -> F::Output {
+    REPOSITORY.scope(repository, f).await
+}
+
+/// The repository of the current [`scope`], e.g. to hand on to a spawned task.
+///
+/// # Panics
+///
+/// Outside of a scope, as there is no backend to query.
+pub fn current() -> Arc<dyn Repository> {
+    REPOSITORY
+        .try_with(Arc::clone)
+        .expect("database helpers must run inside db::scope")
+}
+
+/// Runs a query, recording how long it took under `operation`.
+async fn timed<F: Future>(operation: &'static str, query: F) -> F::Output {
+    let timer = DB_QUERY_SECONDS
+        .with_label_values(&[operation])
+        .start_timer();
+    let output = query.await;
+    timer.observe_duration();
+    output
+}
+
+pub async fn get_like(table: &str, column: &str, value: &str) -> Vec<Vec<Value>> {
+    timed("get_like", current().get_like(table, column, value)).await
+}
+
+pub async fn get_where(table: &str, column: &str, value: &str) -> Vec<Vec<Value>> {
+    timed("get_where", current().get_where(table, column, value)).await
+}
+
+pub async fn get_all_rows(table: &str, sorted: bool) -> Vec<Vec<Value>> {
+    timed("get_all_rows", current().get_all_rows(table, sorted)).await
+}
+
+pub async fn get_some(table: &str, column: &str) -> Vec<Vec<Value>> {
+    timed("get_some", current().get_some(table, column)).await
+}
+
+pub async fn get_column_details(table: &str) -> Vec<Vec<Value>> {
+    timed("get_column_details", current().get_column_details(table)).await
+}
+
+pub async fn insert_row(table: &str, names: Vec<&str>, values: Vec<&str>) -> Result<(), String> {
+    timed("insert_row", current().insert_row(table, names, values)).await
+}
+
+pub async fn change_row_where(
+    table: &str,
+    where_column: &str,
+    where_value: &str,
+    column: &str,
+    value: &str,
+) {
+    timed(
+        "change_row_where",
+        current().change_row_where(table, where_column, where_value, column, value),
+    )
+    .await
+}
+
+pub async fn delete_row_where(table: &str, column: &str, value: &str) {
+    timed(
+        "delete_row_where",
+        current().delete_row_where(table, column, value),
+    )
+    .await
+}
+
+pub async fn get_max_id(table: &str) -> i32 {
+    timed("get_max_id", current().get_max_id(table)).await
+}
+
+pub async fn get_min_id(table: &str) -> i32 {
+    timed("get_min_id", current().get_min_id(table)).await
+}
+
+pub async fn row_exists(table: &str, column: &str, value: &str) -> bool {
+    timed("row_exists", current().row_exists(table, column, value)).await
+}
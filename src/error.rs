@@ -0,0 +1,153 @@
+use serde_json::{json, Value};
+
+use std::fmt;
+use std::io;
+
+use crate::requests::BodyError;
+
+/// Every way a handler can fail. `handle_request` turns these into HTTP status codes.
+#[derive(Debug)]
+pub enum ApiError {
+    SessionMissing,
+    NotAuthorized(String),
+    Database(String),
+    Mail(String),
+    Validation(BodyError),
+    NotFound(String),
+    Code(CodeError),
+    /// The caller must wait `retry_after` seconds before trying again.
+    RateLimited {
+        retry_after: u64,
+    },
+    Io(io::Error),
+    /// The first factor was accepted, but the admin must finish logging in through
+    /// `/verify_totp` with the returned `session`, which is empty when it was only
+    /// sent in the cookie.
+    TotpRequired {
+        session: String,
+    },
+    /// The session came from the cookie without its `X-CSRF-Token` header.
+    CsrfMismatch,
+}
+
+/// Why an emailed verification code was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeError {
+    Incorrect {
+        remaining: u32,
+    },
+    Expired,
+    /// The code was already used successfully.
+    Used,
+    /// Too many wrong guesses were made.
+    Locked,
+}
+
+impl ApiError {
+    pub fn validation(message: &str) -> Self {
+        ApiError::Validation(BodyError {
+            message: message.to_string(),
+            ..Default::default()
+        })
+    }
+    pub fn invalid_field(field: &'static str, message: &str) -> Self {
+        ApiError::Validation(BodyError {
+            message: message.to_string(),
+            invalid: vec![field],
+            ..Default::default()
+        })
+    }
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::SessionMissing => "session_missing",
+            ApiError::NotAuthorized(_) => "not_authorized",
+            ApiError::Database(_) => "database",
+            ApiError::Mail(_) => "mail",
+            ApiError::Validation(_) => "validation",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Code(CodeError::Incorrect { .. }) => "code_incorrect",
+            ApiError::Code(CodeError::Expired) => "code_expired",
+            ApiError::Code(CodeError::Used) => "code_used",
+            ApiError::Code(CodeError::Locked) => "code_locked",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Io(_) => "io",
+            ApiError::TotpRequired { .. } => "totp_required",
+            ApiError::CsrfMismatch => "csrf_mismatch",
+        }
+    }
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::SessionMissing => {
+                "Your session has expired. Please log in again.".to_string()
+            }
+            ApiError::NotAuthorized(t)
+            | ApiError::Database(t)
+            | ApiError::Mail(t)
+            | ApiError::NotFound(t) => t.clone(),
+            ApiError::Validation(e) => e.message.clone(),
+            ApiError::Code(CodeError::Incorrect { remaining }) => format!(
+                "The code you entered is incorrect. {} attempts remaining.",
+                remaining
+            ),
+            ApiError::Code(CodeError::Expired) => {
+                "This code has expired. Please request a new one.".to_string()
+            }
+            ApiError::Code(CodeError::Used) => {
+                "This code has already been used. Please request a new one.".to_string()
+            }
+            ApiError::Code(CodeError::Locked) => {
+                "Too many incorrect attempts. Please request a new code.".to_string()
+            }
+            ApiError::RateLimited { retry_after } => format!(
+                "Too many requests. Please try again in {} seconds.",
+                retry_after
+            ),
+            ApiError::Io(e) => e.to_string(),
+            ApiError::TotpRequired { .. } => {
+                "Please enter the code from your authenticator app.".to_string()
+            }
+            ApiError::CsrfMismatch => {
+                "The X-CSRF-Token header is missing or does not match your session.".to_string()
+            }
+        }
+    }
+    /// The `{ "error": { "code", "message" } }` body sent to the client.
+    pub fn body(&self) -> String {
+        let mut error = json!({ "code": self.code(), "message": self.message() });
+        if let ApiError::Validation(e) = self {
+            if !e.missing.is_empty() {
+                error["missing"] = Value::from(e.missing.clone());
+            }
+            if !e.invalid.is_empty() {
+                error["invalid"] = Value::from(e.invalid.clone());
+            }
+        }
+        match self {
+            ApiError::TotpRequired { session } if !session.is_empty() => {
+                error["session"] = Value::from(session.clone());
+            }
+            _ => {}
+        }
+        json!({ "error": error }).to_string()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<BodyError> for ApiError {
+    fn from(e: BodyError) -> Self {
+        ApiError::Validation(e)
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        ApiError::Io(e)
+    }
+}
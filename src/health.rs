@@ -0,0 +1,119 @@
+use lazy_static::lazy_static;
+use tokio::time::timeout;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::db;
+use crate::locks;
+use crate::responses::{DependencyStatus, Readiness};
+use crate::session_store::Session;
+
+/// How long a single dependency may take before it counts as down.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a Gmail check is reused, as each one exchanges the refresh token with Google.
+const GMAIL_CHECK_TTL: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+    static ref GMAIL_CHECK: Mutex<Option<(Instant, DependencyStatus)>> = Mutex::new(None);
+}
+
+/// Runs a check in its own task, so a panicking or hanging dependency only fails its check.
+async fn check<F>(check: F) -> DependencyStatus
+where
+    F: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let check = db::scope(db::current(), check);
+    let result = match timeout(CHECK_TIMEOUT, tokio::spawn(check)).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("The check panicked.".to_string()),
+        Err(_) => Err(format!(
+            "No answer within {} seconds.",
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    status(result)
+}
+
+fn status(result: Result<(), String>) -> DependencyStatus {
+    DependencyStatus {
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+async fn check_database() -> Result<(), String> {
+    if db::get_column_details("users").await.is_empty() {
+        Err("The users table could not be read.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes, reads back and deletes a throwaway session.
+async fn check_sessions(lifetime_days: u64, id_length: u64) -> Result<(), String> {
+    let mut session = Session::new(lifetime_days, id_length).await?;
+    session.set("readiness_probe", "1".to_string()).await;
+    let stored = match Session::from_id(&session.get_id()).await {
+        Some(mut t) => t.get("readiness_probe").await,
+        None => None,
+    };
+    session.delete().await;
+    match stored.as_deref() {
+        Some("1") => Ok(()),
+        _ => Err("A new session could not be read back.".to_string()),
+    }
+}
+
+async fn check_gmail() -> Result<(), String> {
+    crate::get_access_token()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.message())
+}
+
+fn check_images(config: &Config) -> Result<(), String> {
+    fs::read_dir(&config.image_directory)
+        .map(|_| ())
+        .map_err(|e| format!("{}: {}", config.image_directory.display(), e))
+}
+
+/// The last Gmail check if it is recent enough, otherwise a new one.
+async fn cached_gmail_check() -> DependencyStatus {
+    let cached = locks::lock(&GMAIL_CHECK).clone();
+    if let Some((checked, status)) = cached {
+        if checked.elapsed() < GMAIL_CHECK_TTL {
+            return status;
+        }
+    }
+    let status = check(check_gmail()).await;
+    let entry = Some((Instant::now(), status.clone()));
+    *locks::lock(&GMAIL_CHECK) = entry;
+    status
+}
+
+/// Checks every dependency a request might need. The Gmail check is cached for
+/// `GMAIL_CHECK_TTL`.
+pub async fn readiness(config: &Config) -> Readiness {
+    let (database, sessions, gmail) = tokio::join!(
+        check(check_database()),
+        check(check_sessions(
+            config.session_lifetime_days,
+            config.session_id_length
+        )),
+        cached_gmail_check(),
+    );
+    let mut checks = BTreeMap::new();
+    checks.insert("database", database);
+    checks.insert("sessions", sessions);
+    checks.insert("gmail", gmail);
+    checks.insert("images", status(check_images(config)));
+    Readiness {
+        ready: checks.values().all(|t| t.ok),
+        checks,
+    }
+}
@@ -1,14 +1,14 @@
 use chrono::NaiveDate;
+use lazy_static::lazy_static;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use scrypt::{scrypt_check, scrypt_simple, ScryptParams};
 use serde::Serialize;
-use serde_json::{json, Map, Value};
+use serde_json::{Map, Value};
 
-use mysql::*;
-use session::Session;
+use db::*;
+use session_store::Session;
 
-use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
@@ -16,67 +16,377 @@ use std::iter;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use account_validation::*;
+pub use auth::AuthContext;
+pub use config::Config;
+pub use error::{ApiError, CodeError};
+use permissions::Permission;
+use requests::*;
+use responses::*;
+pub use router::Router;
+use router::*;
+use schema::{ColumnType, Table};
 mod account_validation;
+pub mod auth;
+mod codes;
+pub mod config;
+pub mod cookies;
+pub mod cors;
+pub mod db;
+mod error;
+pub mod health;
+mod locks;
+pub mod logging;
+pub mod metrics;
+pub mod permissions;
+pub mod rate_limit;
+pub mod requests;
+pub mod responses;
+pub mod router;
+pub mod schema;
+pub mod session_store;
+mod sessions;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod totp;
 
-#[derive(Serialize)]
-struct Song {
-    name: String,
-    link: String,
-    role: String,
-}
-
-#[derive(Serialize)]
-struct SongArticle {
-    title: String,
-    text: String,
-    songs: Vec<Song>,
-}
-
-#[derive(Serialize)]
-struct CalendarEvent {
-    id: i64,
-    title: String,
-    date: String,
-    start_time: String,
-    end_time: String,
-    notes: String,
-}
-
-pub async fn formulate_response(url: &str, body: HashMap<&str, &str>) -> String {
-    match url {
-        "/get_songs" => get_songs().await,
-        "/hash_password" => hash_password(body).await,
-        "/get_image_list" => get_image_list(),
-        "/get_calendar_events" => get_calendar_events(body).await,
-        "/signup" => signup(body).await,
-        "/login" => login(body).await,
-        "/admin_login" => admin_login(body).await,
-        "/kill_session" => kill_session(body).await,
-        "/get_account" => get_account(body).await,
-        "/refresh" => refresh(body).await,
-        "/change_subscription" => change_subscription(body).await,
-        "/send_change_email" => send_change_email(body).await,
-        "/send_delete_email" => send_delete_email(body).await,
-        "/change_email" => change_email(body).await,
-        "/delete_account" => delete_account(body).await,
-        "/get_database" => get_database(body).await,
-        "/get_row_titles" => get_row_titles(body).await,
-        "/move_row_to_end" => move_row_to_end(body).await,
-        "/move_row_to_start" => move_row_to_start(body).await,
-        "/delete_row" => delete_row(body).await,
-        "/add_row" => add_row(body).await,
-        "/change_row" => change_row(body).await,
-        "/get_gmail_auth_url" => get_gmail_auth_url(body).await,
-        "/is_gmail_working" => is_gmail_working(body).await,
-        "/send_gmail_code" => send_gmail_code(body).await,
-        "/verify_account" => verify_account(body).await,
-        "/send_email" => send_email(body).await,
-        _ => message(&format!("The provided url {} could not be resolved.", url)),
-    }
-}
-
-fn message(message: &str) -> String {
-    json!({ "message": message }).to_string()
+/// Wraps a handler call so it can be stored in the [`Router`].
+macro_rules! handler {
+    (|$config:pat, $auth:pat, $body:pat| $call:expr) => {
+        |$config, $auth, $body| Box::pin(async move { respond($call.await) })
+    };
+}
+
+/// Every route of the api together with who may call it and how.
+pub fn router() -> Router {
+    let mut router = Router::new();
+    router
+        .add(
+            "/get_songs",
+            Role::Public,
+            GET_POST,
+            RateLimit::Standard,
+            handler!(|_, _, _| get_songs()),
+        )
+        .add(
+            "/get_image_list",
+            Role::Public,
+            GET_POST,
+            RateLimit::Standard,
+            handler!(|config, _, _| get_image_list(config)),
+        )
+        .add(
+            "/get_calendar_events",
+            Role::Public,
+            GET_POST,
+            RateLimit::Standard,
+            handler!(|_, _, body| get_calendar_events(parse(body)?)),
+        )
+        .add(
+            "/signup",
+            Role::Public,
+            POST,
+            RateLimit::Email,
+            handler!(|config, _, body| signup(config, parse(body)?)),
+        )
+        .add(
+            "/login",
+            Role::Public,
+            POST,
+            RateLimit::Email,
+            handler!(|config, _, body| login(config, parse(body)?)),
+        )
+        .add(
+            "/admin_login",
+            Role::Public,
+            POST,
+            RateLimit::Code,
+            handler!(|config, _, body| admin_login(config, parse(body)?)),
+        )
+        .add(
+            "/password_login",
+            Role::Public,
+            POST,
+            RateLimit::Code,
+            handler!(|config, _, body| password_login(config, parse(body)?)),
+        )
+        .add(
+            "/send_password_reset_email",
+            Role::Public,
+            POST,
+            RateLimit::Email,
+            handler!(|config, _, body| send_password_reset_email(config, parse(body)?)),
+        )
+        .add(
+            "/reset_password",
+            Role::Public,
+            POST,
+            RateLimit::Code,
+            handler!(|config, auth, body| reset_password(config, auth, parse(body)?)),
+        )
+        .add(
+            "/send_admin_password_reset_email",
+            Role::Public,
+            POST,
+            RateLimit::Email,
+            handler!(|config, _, body| send_admin_password_reset_email(config, parse(body)?)),
+        )
+        .add(
+            "/reset_admin_password",
+            Role::Public,
+            POST,
+            RateLimit::Code,
+            handler!(|config, auth, body| reset_admin_password(config, auth, parse(body)?)),
+        )
+        .add(
+            "/verify_account",
+            Role::Public,
+            POST,
+            RateLimit::Code,
+            handler!(|config, auth, body| verify_account(config, auth, parse(body)?)),
+        )
+        .add(
+            "/verify_link",
+            Role::Public,
+            GET_POST,
+            RateLimit::Code,
+            handler!(|config, _, body| verify_link(config, parse(body)?)),
+        )
+        .add(
+            "/verify_totp",
+            Role::Public,
+            POST,
+            RateLimit::Code,
+            handler!(|config, auth, body| verify_totp(config, auth, parse(body)?)),
+        )
+        .add(
+            "/kill_session",
+            Role::Public,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| kill_session(auth)),
+        )
+        .add(
+            "/list_sessions",
+            Role::User,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| list_sessions(auth)),
+        )
+        .add(
+            "/revoke_session",
+            Role::User,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| revoke_session(auth, parse(body)?)),
+        )
+        .add(
+            "/revoke_all_other_sessions",
+            Role::User,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| revoke_all_other_sessions(auth)),
+        )
+        .add(
+            "/get_account",
+            Role::User,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| get_account(auth, parse(body)?)),
+        )
+        .add(
+            "/refresh",
+            Role::User,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| refresh(auth)),
+        )
+        .add(
+            "/change_subscription",
+            Role::User,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| change_subscription(auth, parse(body)?)),
+        )
+        .add(
+            "/send_change_email",
+            Role::User,
+            POST,
+            RateLimit::Email,
+            handler!(|config, auth, body| send_change_email(config, auth, parse(body)?)),
+        )
+        .add(
+            "/send_delete_email",
+            Role::User,
+            POST,
+            RateLimit::Email,
+            handler!(|config, auth, _| send_delete_email(config, auth)),
+        )
+        .add(
+            "/change_email",
+            Role::User,
+            POST,
+            RateLimit::Code,
+            handler!(|config, auth, body| change_email(config, auth, parse(body)?)),
+        )
+        .add(
+            "/set_password",
+            Role::User,
+            POST,
+            RateLimit::Code,
+            handler!(|_, auth, body| set_password(auth, parse(body)?)),
+        )
+        .add(
+            "/delete_account",
+            Role::User,
+            POST,
+            RateLimit::Code,
+            handler!(|config, auth, body| delete_account(config, auth, parse(body)?)),
+        )
+        .add(
+            "/hash_password",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, _, body| hash_password(parse(body)?)),
+        )
+        .add(
+            "/change_admin_password",
+            Role::Admin,
+            POST,
+            RateLimit::Code,
+            handler!(|_, auth, body| change_admin_password(auth, parse(body)?)),
+        )
+        .add(
+            "/enroll_totp",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| enroll_totp(auth)),
+        )
+        .add(
+            "/confirm_totp",
+            Role::Admin,
+            POST,
+            RateLimit::Code,
+            handler!(|_, auth, body| confirm_totp(auth, parse(body)?)),
+        )
+        .add(
+            "/disable_totp",
+            Role::Admin,
+            POST,
+            RateLimit::Code,
+            handler!(|_, auth, body| disable_totp(auth, parse(body)?)),
+        )
+        .add(
+            "/get_schema",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| get_schema(auth)),
+        )
+        .add(
+            "/get_database",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| get_database(auth, parse(body)?)),
+        )
+        .add(
+            "/get_row_titles",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| get_row_titles(auth, parse(body)?)),
+        )
+        .add(
+            "/move_row_to_end",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| move_row_to_end(auth, parse(body)?)),
+        )
+        .add(
+            "/move_row_to_start",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| move_row_to_start(auth, parse(body)?)),
+        )
+        .add(
+            "/delete_row",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|config, auth, body| delete_row(config, auth, parse(body)?)),
+        )
+        .add(
+            "/add_row",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| add_row(auth, parse(body)?)),
+        )
+        .add(
+            "/change_row",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|config, auth, body| change_row(config, auth, parse(body)?)),
+        )
+        .add(
+            "/get_gmail_auth_url",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|config, auth, _| get_gmail_auth_url(config, auth)),
+        )
+        .add(
+            "/is_gmail_working",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, _, _| is_gmail_working()),
+        )
+        .add(
+            "/send_gmail_code",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| send_gmail_code(auth, parse(body)?)),
+        )
+        .add(
+            "/send_email",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| send_email(auth, parse(body)?)),
+        )
+        .add(
+            "/get_permissions",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, _| get_permissions(auth)),
+        )
+        .add(
+            "/grant_permission",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| grant_permission(auth, parse(body)?)),
+        )
+        .add(
+            "/revoke_permission",
+            Role::Admin,
+            POST,
+            RateLimit::Standard,
+            handler!(|_, auth, body| revoke_permission(auth, parse(body)?)),
+        );
+    router
+}
+
+fn respond<T: Serialize>(response: Result<T, ApiError>) -> Result<String, ApiError> {
+    response.map(|t| serde_json::to_string(&t).unwrap())
 }
 fn hash(to_hash: &str) -> String {
     scrypt_simple(to_hash, &ScryptParams::new(12, 8, 1).unwrap()).unwrap()
@@ -85,7 +395,97 @@ fn hash_match(password: &str, hash: &str) -> bool {
     scrypt_check(password, hash).is_ok()
 }
 
-pub async fn get_songs() -> String {
+async fn new_session(config: &Config) -> Result<Session, ApiError> {
+    Session::new(config.session_lifetime_days, config.session_id_length)
+        .await
+        .map_err(ApiError::Database)
+}
+async fn get_var(session: &mut Session, key: &str) -> Result<String, ApiError> {
+    session.get(key).await.ok_or(ApiError::SessionMissing)
+}
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Generates a new code, stores its hash under `key` with its issue time and resets its
+/// failed attempts. Returns the plain code to be emailed.
+async fn store_code(config: &Config, session: &mut Session, key: &str) -> String {
+    let code = generate_verification_code();
+    let hash = codes::hash(&config.code_secret, &session.get_id(), key, &code);
+    session
+        .set(key, hash)
+        .await
+        .set(&format!("{}_issued", key), unix_time().to_string())
+        .await
+        .set(&format!("{}_attempts", key), "0".to_string())
+        .await;
+    code
+}
+
+async fn get_number(session: &mut Session, key: &str) -> u64 {
+    session
+        .get(key)
+        .await
+        .and_then(|t| t.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Compares a submitted code with the one stored under `key`. A code works once, for
+/// `code_ttl_minutes`, and is wiped after `max_code_attempts` wrong guesses.
+async fn check_code(
+    config: &Config,
+    session: &mut Session,
+    key: &str,
+    code: &str,
+) -> Result<(), ApiError> {
+    let stored = get_var(session, key).await?;
+    let attempts_key = format!("{}_attempts", key);
+    let attempts = get_number(session, &attempts_key).await as u32;
+    let max_attempts = config.rate_limit.max_code_attempts;
+    // Checked first, so every attempt after the code expires is told so.
+    let issued = get_number(session, &format!("{}_issued", key)).await;
+    if issued != 0 && unix_time().saturating_sub(issued) > config.code_ttl_minutes * 60 {
+        if !stored.is_empty() {
+            session.set(key, String::new()).await;
+        }
+        metrics::CODE_ATTEMPTS
+            .with_label_values(&[key, "expired"])
+            .inc();
+        return Err(ApiError::Code(CodeError::Expired));
+    }
+    if stored.is_empty() {
+        return Err(ApiError::Code(if attempts >= max_attempts {
+            CodeError::Locked
+        } else {
+            CodeError::Used
+        }));
+    }
+    let correct = codes::matches(&config.code_secret, &session.get_id(), key, code, &stored);
+    metrics::CODE_ATTEMPTS
+        .with_label_values(&[key, if correct { "correct" } else { "wrong" }])
+        .inc();
+    if correct {
+        session.set(key, String::new()).await;
+        return Ok(());
+    }
+    let attempts = attempts + 1;
+    session.set(&attempts_key, attempts.to_string()).await;
+    if attempts >= max_attempts {
+        session.set(key, String::new()).await;
+        return Err(ApiError::Code(CodeError::Locked));
+    }
+    Err(ApiError::Code(CodeError::Incorrect {
+        remaining: max_attempts - attempts,
+    }))
+}
+async fn is_set(session: &mut Session, key: &str) -> bool {
+    session.get(key).await.unwrap_or_default() == "1"
+}
+
+pub async fn get_songs() -> Result<SongArticle, ApiError> {
     let mut expiry = 0;
     let current_time = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -122,22 +522,26 @@ pub async fn get_songs() -> String {
                     role: from_value(x[3].clone()),
                 })
                 .collect();
-            serde_json::to_string(&t).unwrap()
+            Ok(t)
         }
-        None => json!({"title" : ""}).to_string(),
+        None => Ok(SongArticle::default()),
     }
 }
 
-pub fn get_image_list() -> String {
-    let paths: Vec<String> = fs::read_dir("/srv/http/images/")
-        .unwrap()
-        .map(|x| x.unwrap().file_name().into_string().unwrap())
-        .collect();
-    json!({ "images": paths }).to_string()
+pub async fn get_image_list(config: &Config) -> Result<ImageList, ApiError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&config.image_directory)? {
+        if let Ok(name) = entry?.file_name().into_string() {
+            paths.push(name);
+        }
+    }
+    Ok(ImageList { images: paths })
 }
 
-pub async fn get_calendar_events(body: HashMap<&str, &str>) -> String {
-    let result: Vec<CalendarEvent> = get_like("calendar", "date", body["year_month"])
+pub async fn get_calendar_events(
+    body: CalendarEventsRequest,
+) -> Result<Vec<CalendarEvent>, ApiError> {
+    Ok(get_like("calendar", "date", &body.year_month)
         .await
         .iter()
         .map(|x| CalendarEvent {
@@ -150,14 +554,13 @@ pub async fn get_calendar_events(body: HashMap<&str, &str>) -> String {
             end_time: from_value(x[4].clone()),
             notes: from_value(x[6].clone()),
         })
-        .collect();
-    serde_json::to_string(&result).unwrap()
+        .collect())
 }
 
-pub async fn signup(body: HashMap<&str, &str>) -> String {
-    let email = body["email"].to_lowercase();
+pub async fn signup(config: &Config, body: EmailRequest) -> Result<LoginEmail, ApiError> {
+    let email = body.email.to_lowercase();
     if let Some(t) = check_email(&email).await {
-        return message(t);
+        return Err(ApiError::invalid_field("email", t));
     }
     insert_row(
         "users",
@@ -165,48 +568,169 @@ pub async fn signup(body: HashMap<&str, &str>) -> String {
         vec![&email, "1"],
     )
     .await
-    .unwrap();
-    let mut session = Session::new(30, 100).await;
-    match refresh_user_session(&mut session, "email", email.clone(), "0").await {
-        Some(t) => message(&t),
-        None => send_login_email(&mut session).await,
+    .map_err(ApiError::Database)?;
+    let mut session = new_session(config).await?;
+    refresh_user_session(&mut session, "email", email, "0").await?;
+    send_login_email(config, &mut session).await
+}
+
+pub async fn login(config: &Config, body: EmailRequest) -> Result<LoginEmail, ApiError> {
+    let email = body.email.to_lowercase();
+    let mut session = new_session(config).await?;
+    refresh_user_session(&mut session, "email", email, "0").await?;
+    send_login_email(config, &mut session).await
+}
+
+async fn send_login_email(config: &Config, session: &mut Session) -> Result<LoginEmail, ApiError> {
+    let email = get_var(session, "not_verified_email").await?;
+    let verification_code = store_code(config, session, "verification_code").await;
+    let link = if config.login_link_url.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "To log in with one click, open this link: {}?token={}\r\n\r\nOr, ",
+            config.login_link_url,
+            store_login_link(config, &session.get_id()).await?
+        )
+    };
+    let body = format!("Hello,\r\n{}To verify your identity, please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you received it in error please contact {}", link, verification_code, config.contact_email);
+    send_code_mail(config, &email, "Verify Your Identity", &body).await?;
+    Ok(LoginEmail {
+        session: session.get_id(),
+        email,
+    })
+}
+
+pub async fn admin_login(
+    config: &Config,
+    body: PasswordLoginRequest,
+) -> Result<SessionId, ApiError> {
+    let email = body.email.to_lowercase();
+    let mut session = new_session(config).await?;
+    refresh_admin_session(&mut session, "email", email, Some(&body.password)).await?;
+    Ok(SessionId {
+        session: session.get_id(),
+    })
+}
+
+/// The password hash of a user, empty if they only log in with emailed codes.
+async fn user_password_hash(key: &str, value: &str) -> Option<String> {
+    let user = get_where("users", key, value).await.into_iter().next()?;
+    Some(from_value::<Option<String>>(user[3].clone()).unwrap_or_default())
+}
+
+pub async fn password_login(
+    config: &Config,
+    body: PasswordLoginRequest,
+) -> Result<SessionId, ApiError> {
+    let email = body.email.to_lowercase();
+    let hash = user_password_hash("email", &email).await.ok_or_else(|| {
+        ApiError::NotFound(
+            "This email address is not registered. Please create a new account.".to_string(),
+        )
+    })?;
+    if hash.is_empty() {
+        return Err(ApiError::invalid_field(
+            "password",
+            "This account has no password. Please log in with an emailed code.",
+        ));
+    }
+    if !hash_match(&body.password, &hash) {
+        return Err(ApiError::invalid_field(
+            "password",
+            "Wrong password, please try again.",
+        ));
     }
+    let mut session = new_session(config).await?;
+    refresh_user_session(&mut session, "email", email, "1").await?;
+    Ok(SessionId {
+        session: session.get_id(),
+    })
 }
 
-pub async fn login(body: HashMap<&str, &str>) -> String {
-    let email = body["email"].to_lowercase();
-    let mut session = Session::new(30, 100).await;
-    match refresh_user_session(&mut session, "email", email.clone(), "0").await {
-        Some(t) => message(&t),
-        None => send_login_email(&mut session).await,
+/// Sets or changes the caller's password, logging out their other sessions. Changing
+/// one needs the current password; a forgotten one is replaced through `/reset_password`.
+pub async fn set_password(
+    mut auth: AuthContext,
+    body: SetPasswordRequest,
+) -> Result<Success, ApiError> {
+    if auth.is_admin() {
+        return Err(ApiError::NotAuthorized(
+            "Administrators change their password through /change_admin_password.".to_string(),
+        ));
+    }
+    let id = auth.user_id()?;
+    if let Some(t) = check_password(&body.password) {
+        return Err(ApiError::invalid_field("password", t));
+    }
+    let current = user_password_hash("id", id)
+        .await
+        .ok_or_else(|| ApiError::NotFound("Your account no longer exists.".to_string()))?;
+    if !current.is_empty() {
+        match &body.current_password {
+            Some(t) if hash_match(t, &current) => {}
+            Some(_) => {
+                return Err(ApiError::invalid_field(
+                    "current_password",
+                    "Wrong password, please try again.",
+                ))
+            }
+            None => {
+                return Err(ApiError::invalid_field(
+                    "current_password",
+                    "Please enter your current password.",
+                ))
+            }
+        }
     }
+    change_row_where("users", "id", id, "password", &hash(&body.password)).await;
+    let account = auth.account()?;
+    sessions::revoke_all(&account, &auth.session()?.get_id()).await;
+    Ok(Success { success: true })
 }
 
-async fn send_login_email(session: &mut Session) -> String {
-    let email = session.get("not_verified_email").await.unwrap();
-    let verification_code = generate_verification_code();
-    session
-        .set("verification_code", verification_code.clone())
-        .await;
-    let access_token = get_access_token().await.unwrap();
-    let body = format!("Hello,\r\nTo verify your identity, please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you received it in error please contact justus@olmmcc.tk", verification_code);
-    gmail::send_email(
-        vec![email.clone()],
-        "Verify Your Identity",
-        &body,
-        &access_token,
-    )
-    .await;
-    json!({"session" : session.get_id(), "email": email}).to_string()
+/// Emails a code which lets a user choose a new password, logging them in.
+pub async fn send_password_reset_email(
+    config: &Config,
+    body: EmailRequest,
+) -> Result<LoginEmail, ApiError> {
+    let email = body.email.to_lowercase();
+    if user_password_hash("email", &email).await.is_none() {
+        return Err(ApiError::NotFound(
+            "This email address is not registered. Please create a new account.".to_string(),
+        ));
+    }
+    let mut session = new_session(config).await?;
+    refresh_user_session(&mut session, "email", email, "0").await?;
+    let email = get_var(&mut session, "not_verified_email").await?;
+    let reset_code = store_code(config, &mut session, "password_reset_code").await;
+    let body = format!("Hello,\r\nYou requested a new password for your OLMMCC account. Please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you did not make this request please contact {}", reset_code, config.contact_email);
+    send_code_mail(config, &email, "Reset your Password", &body).await?;
+    Ok(LoginEmail {
+        session: session.get_id(),
+        email,
+    })
 }
 
-pub async fn admin_login(body: HashMap<&str, &str>) -> String {
-    let email = body["email"].to_lowercase();
-    let mut session = Session::new(30, 100).await;
-    match refresh_admin_session(&mut session, "email", email, Some(body["password"])).await {
-        Some(t) => message(&t),
-        None => json!({"session" : session.get_id()}).to_string(),
+/// Sets a forgotten password and logs the user in, logging out their other sessions.
+pub async fn reset_password(
+    config: &Config,
+    mut auth: AuthContext,
+    body: ResetPasswordRequest,
+) -> Result<Verified, ApiError> {
+    if let Some(t) = check_password(&body.password) {
+        return Err(ApiError::invalid_field("password", t));
     }
+    let session = auth.session()?;
+    check_code(config, session, "password_reset_code", &body.code).await?;
+    let id = get_var(session, "id").await?;
+    change_row_where("users", "id", &id, "password", &hash(&body.password)).await;
+    complete_verification(config, session).await?;
+    sessions::revoke_all(&sessions::user_account(&id), &session.get_id()).await;
+    Ok(Verified {
+        success: true,
+        session: session.get_id(),
+    })
 }
 
 async fn refresh_user_session(
@@ -214,14 +738,16 @@ async fn refresh_user_session(
     key: &str,
     value: String,
     verified: &str,
-) -> Option<String> {
+) -> Result<(), ApiError> {
     session.clear().await;
-    let users = get_like("users", key, &value).await;
-    if let Some(user) = users.iter().next() {
+    let users = get_where("users", key, &value).await;
+    if let Some(user) = users.first() {
         session
             .set("id", from_value::<i32>(user[1].clone()).to_string())
             .await;
         if verified == "1" {
+            let id = from_value::<i32>(user[1].clone()).to_string();
+            sessions::register(&session.get_id(), &sessions::user_account(&id)).await?;
             session
                 .set("verified", "1".to_string())
                 .await
@@ -234,18 +760,17 @@ async fn refresh_user_session(
                     from_value::<i32>(user[2].clone()).to_string(),
                 )
                 .await;
-            None
         } else {
             session
                 .set("verified", "0".to_string())
                 .await
                 .set("not_verified_email", from_value(user[0].clone()))
                 .await;
-            None
         }
+        Ok(())
     } else {
-        let admin = get_like("admin", key, &value).await;
-        if let Some(admin) = admin.iter().next() {
+        let admin = get_where("admin", key, &value).await;
+        if let Some(admin) = admin.first() {
             session
                 .set("id", from_value::<i32>(admin[2].clone()).to_string())
                 .await
@@ -255,30 +780,55 @@ async fn refresh_user_session(
                 .await
                 .set("not_verified_email", from_value(admin[0].clone()))
                 .await;
-            None
+            Ok(())
         } else {
-            Some("This email address is not registered. Please create a new account.".to_string())
+            Err(ApiError::NotFound(
+                "This email address is not registered. Please create a new account.".to_string(),
+            ))
         }
     }
 }
 
+/// Logs a session in as an admin. Admins with an authenticator app enrolled are only left
+/// waiting for `/verify_totp`, unless this session already passed it.
 async fn refresh_admin_session(
     session: &mut Session,
     key: &str,
     value: String,
     password: Option<&str>,
-) -> Option<String> {
+) -> Result<(), ApiError> {
+    let totp_verified = is_set(session, "totp_verified").await;
     session.clear().await;
-    let users = get_like("admin", key, &value).await;
-    if let Some(user) = users.iter().next() {
+    let users = get_where("admin", key, &value).await;
+    if let Some(user) = users.first() {
         if let Some(p) = password {
             if !hash_match(p, &from_value::<String>(user[1].clone())) {
-                return Some("Wrong password, please try again.".to_string());
+                return Err(ApiError::invalid_field(
+                    "password",
+                    "Wrong password, please try again.",
+                ));
             }
         }
-        session
-            .set("id", from_value::<i32>(user[2].clone()).to_string())
-            .await;
+        let id = from_value::<i32>(user[2].clone()).to_string();
+        session.set("id", id.clone()).await;
+        if !totp_verified && totp_row(&id).await.is_some() {
+            session
+                .set("totp_pending", "1".to_string())
+                .await
+                .set("not_verified_admin", "1".to_string())
+                .await
+                .set("verified", "0".to_string())
+                .await
+                .set("not_verified_email", from_value(user[0].clone()))
+                .await;
+            return Err(ApiError::TotpRequired {
+                session: session.get_id(),
+            });
+        }
+        if totp_verified {
+            session.set("totp_verified", "1".to_string()).await;
+        }
+        sessions::register(&session.get_id(), &sessions::admin_account(&id)).await?;
         session
             .set("email", from_value(user[0].clone()))
             .await
@@ -289,391 +839,516 @@ async fn refresh_admin_session(
                 from_value::<i32>(user[3].clone()).to_string(),
             )
             .await;
-        None
+        Ok(())
     } else {
-        Some("This account is not an administrator account.".to_string())
-    }
-}
-
-pub async fn get_account(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("verified").await.unwrap_or_default() == "1"
-            || session.get("admin").await.unwrap_or_default() == "1"
-        {
-            const ALLOWED_VARS: &[&str] = &["email", "admin", "subscription_policy"];
-            let mut map = Map::new();
-            for var in ALLOWED_VARS {
-                if body["details"].contains(var) {
-                    map.insert(
-                        var.to_string(),
-                        Value::String(session.get(var).await.unwrap()),
-                    );
-                }
-            }
-            return serde_json::to_string(&map).unwrap();
+        Err(ApiError::NotAuthorized(
+            "This account is not an administrator account.".to_string(),
+        ))
+    }
+}
+
+pub async fn change_admin_password(
+    mut auth: AuthContext,
+    body: ChangePasswordRequest,
+) -> Result<Success, ApiError> {
+    let id = auth.user_id()?.to_string();
+    if let Some(t) = check_password(&body.password) {
+        return Err(ApiError::invalid_field("password", t));
+    }
+    let admin = get_like("admin", "id", &id)
+        .await
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::NotFound("Your account no longer exists.".to_string()))?;
+    if !hash_match(
+        &body.current_password,
+        &from_value::<String>(admin[1].clone()),
+    ) {
+        return Err(ApiError::invalid_field(
+            "current_password",
+            "Wrong password, please try again.",
+        ));
+    }
+    change_row_where("admin", "id", &id, "password", &hash(&body.password)).await;
+    sessions::revoke_all(&sessions::admin_account(&id), &auth.session()?.get_id()).await;
+    Ok(Success { success: true })
+}
+
+/// Emails an admin a code which lets them choose a new password.
+pub async fn send_admin_password_reset_email(
+    config: &Config,
+    body: EmailRequest,
+) -> Result<LoginEmail, ApiError> {
+    let admin = get_where("admin", "email", &body.email.to_lowercase())
+        .await
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            ApiError::NotFound("This email address is not an administrator account.".to_string())
+        })?;
+    let email = from_value::<String>(admin[0].clone());
+    let mut session = new_session(config).await?;
+    session
+        .set("id", from_value::<i32>(admin[2].clone()).to_string())
+        .await
+        .set("admin_password_reset", "1".to_string())
+        .await;
+    let reset_code = store_code(config, &mut session, "admin_password_reset_code").await;
+    let body = format!("Hello,\r\nYou requested a new password for your OLMMCC administrator account. Please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you did not make this request please contact {}", reset_code, config.contact_email);
+    send_code_mail(config, &email, "Reset your Password", &body).await?;
+    Ok(LoginEmail {
+        session: session.get_id(),
+        email,
+    })
+}
+
+/// Sets a new admin password and logs out all of that admin's sessions. The admin then
+/// logs in again through `/admin_login`, so two-factor authentication still applies.
+pub async fn reset_admin_password(
+    config: &Config,
+    mut auth: AuthContext,
+    body: ResetPasswordRequest,
+) -> Result<Success, ApiError> {
+    if let Some(t) = check_password(&body.password) {
+        return Err(ApiError::invalid_field("password", t));
+    }
+    let session = auth.session()?;
+    if !is_set(session, "admin_password_reset").await {
+        return Err(ApiError::validation(
+            "This session is not resetting an administrator password.",
+        ));
+    }
+    check_code(config, session, "admin_password_reset_code", &body.code).await?;
+    let id = get_var(session, "id").await?;
+    change_row_where("admin", "id", &id, "password", &hash(&body.password)).await;
+    sessions::revoke_all(&sessions::admin_account(&id), "").await;
+    session.delete().await;
+    Ok(Success { success: true })
+}
+
+pub async fn get_account(
+    mut auth: AuthContext,
+    body: GetAccountRequest,
+) -> Result<Map<String, Value>, ApiError> {
+    let session = auth.session()?;
+    const ALLOWED_VARS: &[&str] = &["email", "admin", "subscription_policy"];
+    let mut map = Map::new();
+    for var in ALLOWED_VARS {
+        if body.details.contains(var) {
+            map.insert(var.to_string(), Value::String(get_var(session, var).await?));
         }
     }
-    json!({"session" : "none"}).to_string()
+    Ok(map)
 }
 
-pub async fn kill_session(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        session.delete().await;
+pub async fn kill_session(auth: AuthContext) -> Result<Empty, ApiError> {
+    if let Some(session) = auth.session {
+        sessions::end(session).await;
     }
-    json!({}).to_string()
+    Ok(Empty {})
 }
 
-pub async fn refresh(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        let id = session.get("id").await.unwrap();
-        let verified = &session.get("verified").await.unwrap();
-        refresh_user_session(&mut session, "id", id, verified)
-            .await
-            .unwrap();
+pub async fn list_sessions(mut auth: AuthContext) -> Result<Vec<SessionInfo>, ApiError> {
+    let account = auth.account()?;
+    Ok(sessions::list(&account, &auth.session()?.get_id()).await)
+}
+
+pub async fn revoke_session(
+    auth: AuthContext,
+    body: RevokeSessionRequest,
+) -> Result<Success, ApiError> {
+    sessions::revoke(&auth.account()?, &body.handle).await?;
+    Ok(Success { success: true })
+}
+
+pub async fn revoke_all_other_sessions(mut auth: AuthContext) -> Result<Success, ApiError> {
+    let account = auth.account()?;
+    sessions::revoke_all(&account, &auth.session()?.get_id()).await;
+    Ok(Success { success: true })
+}
+
+/// Reloads the caller's session from their row in the `users` or `admin` table.
+pub async fn refresh(mut auth: AuthContext) -> Result<Empty, ApiError> {
+    let id = auth.user_id()?.to_string();
+    let admin = auth.is_admin();
+    let session = auth.session()?;
+    if admin {
+        refresh_admin_session(session, "id", id, None).await?;
+    } else {
+        refresh_user_session(session, "id", id, "1").await?;
     }
-    json!({}).to_string()
+    Ok(Empty {})
 }
 
-pub async fn change_subscription(body: HashMap<&str, &str>) -> String {
+pub async fn change_subscription(
+    mut auth: AuthContext,
+    body: ChangeSubscriptionRequest,
+) -> Result<Message, ApiError> {
     const SUBSCRIPTION_MESSAGES: &[&str] = &[
         "You are now unsubscribed from receiving emails.",
         "You are now subscribed to receive emails.",
         "You are now subscribed to receive emails and reminders.",
     ];
-    let mut session = Session::from_id(body["session"]).await.unwrap();
-    if let Some(t) = check_subscription(body["subscription"]) {
-        return message(&t);
+    if let Some(t) = check_subscription(&body.subscription) {
+        return Err(ApiError::invalid_field("subscription", t));
     }
+    let policy = &body.subscription;
     change_row_where(
         "users",
         "id",
-        &session.get("id").await.unwrap(),
+        auth.user_id()?,
         "subscription_policy",
-        body["subscription"],
+        policy,
     )
     .await;
-    session
-        .set("subscription_policy", body["subscription"].to_string())
+    auth.session()?
+        .set("subscription_policy", policy.to_string())
         .await;
-    message(SUBSCRIPTION_MESSAGES[body["subscription"].parse::<usize>().unwrap()])
+    // check_subscription only allows the digits indexing the messages.
+    let message = SUBSCRIPTION_MESSAGES[policy.parse::<usize>().unwrap_or_default()];
+    Ok(Message::new(message))
 }
 
-async fn queue_change_email(session: &mut Session, new_email: &str) -> String {
-    let email = session.get("email").await.unwrap();
-    let email_change_code = generate_verification_code();
-    session
-        .set("email_change_code", email_change_code.clone())
-        .await;
+async fn queue_change_email(
+    config: &Config,
+    session: &mut Session,
+    new_email: &str,
+) -> Result<String, ApiError> {
+    let email = get_var(session, "email").await?;
+    let email_change_code = store_code(config, session, "email_change_code").await;
     session.set("new_email", new_email.to_string()).await;
-    let body = format!("Hello,\r\nYou requested a change of your email address to {}. Please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you did not make this request please contact justus@olmmcc.tk", new_email, email_change_code);
-    let access_token = get_access_token().await.unwrap();
-    gmail::send_email(
-        vec![email.clone()],
-        "Verify your Email Change Request",
-        &body,
-        &access_token,
-    )
-    .await;
-    email
+    let body = format!("Hello,\r\nYou requested a change of your email address to {}. Please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you did not make this request please contact {}", new_email, email_change_code, config.contact_email);
+    send_code_mail(config, &email, "Verify your Email Change Request", &body).await?;
+    Ok(email)
 }
 
-pub async fn send_change_email(body: HashMap<&str, &str>) -> String {
-    let mut session = Session::from_id(body["session"]).await.unwrap();
-    if session.get("verified").await.unwrap() == "1" {
-        if let Some(t) = check_email(body["email"]).await {
-            return message(t);
-        }
-        json!({ "success": true, "email": queue_change_email(&mut session, body["email"]).await })
-            .to_string()
-    } else {
-        json!({ "success": false }).to_string()
-    }
-}
-
-pub async fn change_email(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        let admin = session.get("admin").await.unwrap() == "1";
-        if admin || session.get("verified").await.unwrap() == "1" {
-            if session.get("email_change_code").await.unwrap() == body["code"] {
-                let id = session.get("id").await.unwrap();
-                let new_email = session.get("new_email").await.unwrap();
-                if admin {
-                    change_row_where("admin", "id", &id, "email", &new_email).await;
-                    refresh_admin_session(&mut session, "id", id, None).await;
-                } else {
-                    change_row_where("users", "id", &id, "email", &new_email).await;
-                    refresh_user_session(&mut session, "id", id, "0").await;
-                }
-                return json!({ "success": true }).to_string();
-            }
-        }
-        println!(
-            "{:?} {}",
-            session.get("email_change_code").await,
-            body["code"]
-        );
+pub async fn send_change_email(
+    config: &Config,
+    mut auth: AuthContext,
+    body: SendChangeEmailRequest,
+) -> Result<QueuedEmail, ApiError> {
+    if let Some(t) = check_email(&body.email).await {
+        return Err(ApiError::invalid_field("email", t));
     }
-    json!({"success": false}).to_string()
+    Ok(QueuedEmail {
+        success: true,
+        email: queue_change_email(config, auth.session()?, &body.email).await?,
+    })
 }
 
-pub async fn send_delete_email(body: HashMap<&str, &str>) -> String {
-    let mut session = Session::from_id(body["session"]).await.unwrap();
-    if session.get("admin").await.unwrap() == "1" || session.get("verified").await.unwrap() == "1" {
-        json!({ "success": true, "email": queue_delete_email(&mut session).await }).to_string()
+pub async fn change_email(
+    config: &Config,
+    mut auth: AuthContext,
+    body: CodeRequest,
+) -> Result<Success, ApiError> {
+    let admin = auth.is_admin();
+    let id = auth.user_id()?.to_string();
+    let account = auth.account()?;
+    let session = auth.session()?;
+    check_code(config, session, "email_change_code", &body.code).await?;
+    let new_email = get_var(session, "new_email").await?;
+    sessions::revoke_all(&account, &session.get_id()).await;
+    if admin {
+        change_row_where("admin", "id", &id, "email", &new_email).await;
+        refresh_admin_session(session, "id", id, None).await?;
     } else {
-        json!({ "success": false }).to_string()
+        change_row_where("users", "id", &id, "email", &new_email).await;
+        refresh_user_session(session, "id", id, "0").await?;
     }
+    Ok(Success { success: true })
 }
 
-async fn queue_delete_email(session: &mut Session) -> String {
-    let email = session.get("email").await.unwrap();
-    let delete_code = generate_verification_code();
-    session.set("delete_code", delete_code.clone()).await;
-    let body = format!("Hello,\r\nYou requested a deletion of your OLMMCC account. Please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you did not make this request please contact justus@olmmcc.tk", delete_code);
-    let access_token = get_access_token().await.unwrap();
-    gmail::send_email(
-        vec![email.clone()],
+pub async fn send_delete_email(
+    config: &Config,
+    mut auth: AuthContext,
+) -> Result<QueuedEmail, ApiError> {
+    Ok(QueuedEmail {
+        success: true,
+        email: queue_delete_email(config, auth.session()?).await?,
+    })
+}
+
+async fn queue_delete_email(config: &Config, session: &mut Session) -> Result<String, ApiError> {
+    let email = get_var(session, "email").await?;
+    let delete_code = store_code(config, session, "delete_code").await;
+    let body = format!("Hello,\r\nYou requested a deletion of your OLMMCC account. Please copy this code and return to OLMMCC's website: {}\r\n\r\nThis message was sent by the OLMMCC automated system. If you did not make this request please contact {}", delete_code, config.contact_email);
+    send_code_mail(
+        config,
+        &email,
         "Verify your Account Deletion Request",
         &body,
-        &access_token,
     )
-    .await;
-    email
-}
-
-pub async fn delete_account(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        let admin = session.get("admin").await.unwrap() == "1";
-        if admin || session.get("verified").await.unwrap() == "1" {
-            if session.get("delete_code").await.unwrap() == body["code"] {
-                let id = session.get("id").await.unwrap();
-                if admin {
-                    delete_row_where("admin", "id", &id).await;
-                } else {
-                    delete_row_where("users", "id", &id).await;
-                }
-                return json!({ "success": true }).to_string();
-            }
-        }
-    }
-    json!({"success": false}).to_string()
+    .await?;
+    Ok(email)
 }
 
-async fn get_column_types(table: &str) -> Vec<String> {
-    let mut column_types = Vec::new();
-    for column in get_column_details(table).await {
-        column_types.push(from_value::<String>(column[1].clone()));
+pub async fn delete_account(
+    config: &Config,
+    mut auth: AuthContext,
+    body: CodeRequest,
+) -> Result<Success, ApiError> {
+    check_code(config, auth.session()?, "delete_code", &body.code).await?;
+    let table = if auth.is_admin() { "admin" } else { "users" };
+    delete_row_where(table, "id", auth.user_id()?).await;
+    if auth.is_admin() {
+        delete_row_where("admin_totp", "admin_id", auth.user_id()?).await;
     }
-    column_types
+    let account = auth.account()?;
+    let session = auth.session.take().ok_or(ApiError::SessionMissing)?;
+    sessions::revoke_all(&account, &session.get_id()).await;
+    sessions::end(session).await;
+    Ok(Success { success: true })
 }
 
-pub async fn get_database(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let mut column_names = Vec::new();
-            for column in get_column_details(body["table"]).await {
-                column_names.push(from_value::<String>(column[0].clone()));
-            }
-            let mut processed_rows = Vec::new();
-            let column_types = get_column_types(body["table"]).await;
-            for row in get_all_rows(body["table"], true).await {
-                let mut new_row = Vec::new();
-                for i in 0..row.len() {
-                    push_value(
-                        &column_types[i],
-                        &mut MyValue::from(row[i].clone()),
-                        &mut new_row,
-                    );
-                }
-                processed_rows.push(new_row);
-            }
-            return json!({"success": true, "columns" : column_names, "rows" : processed_rows, "types" : column_types}).to_string();
-        }
-    }
-    json!({"success": false}).to_string()
+/// Looks up a registered table and checks the caller may touch it.
+fn editable_table(auth: &AuthContext, name: &str) -> Result<&'static Table, ApiError> {
+    let table = schema::table(name)?;
+    auth.require(table.permission)?;
+    Ok(table)
 }
 
-fn push_value(column_type: &str, value: &mut MyValue, vec: &mut Vec<String>) {
-    if column_type.contains("date") {
-        vec.push(from_value::<NaiveDate>(value.get()).to_string())
-    } else if column_type.contains("int") {
-        vec.push(from_value::<i32>(value.get()).to_string())
-    } else {
-        vec.push(from_value::<String>(value.get()).to_string());
+fn check_row_id(id: &str) -> Result<(), ApiError> {
+    match id.parse::<i32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ApiError::invalid_field(
+            "id",
+            "Please provide a numeric row id.",
+        )),
     }
 }
 
-pub async fn get_row_titles(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let mut titles: Vec<String> = Vec::new();
-            for title in get_some(body["table"], "title").await {
-                titles.push(from_value(title[0].clone()));
-            }
-            return json!({"table" : body["table"], "titles" : titles}).to_string();
-        }
+pub async fn get_schema(auth: AuthContext) -> Result<Vec<&'static Table>, ApiError> {
+    Ok(schema::TABLES
+        .iter()
+        .filter(|t| auth.has(t.permission))
+        .collect())
+}
+
+pub async fn get_database(
+    auth: AuthContext,
+    body: TableRequest,
+) -> Result<DatabaseTable, ApiError> {
+    let table = editable_table(&auth, &body.table)?;
+    let mut processed_rows = Vec::new();
+    for row in get_all_rows(table.name, true).await {
+        processed_rows.push(format_row(table, row));
     }
-    json!({}).to_string()
+    Ok(DatabaseTable {
+        success: true,
+        columns: table.visible_columns().map(|t| t.name).collect(),
+        rows: processed_rows,
+        types: table.visible_columns().map(|t| t.column_type).collect(),
+    })
 }
 
-async fn return_row(table: &str, id: i32) -> Vec<String> {
-    let row = get_like(table, "id", &id.to_string()).await[0].clone();
+/// Formats a row for the admin UI, leaving out its secret columns.
+fn format_row(table: &Table, row: Vec<db::Value>) -> Vec<String> {
     let mut formatted_row = Vec::new();
-    let column_types = get_column_types(table).await;
-    for i in 0..row.len() {
-        push_value(
-            &column_types[i],
-            &mut MyValue::from(row[i].clone()),
-            &mut formatted_row,
-        );
+    for (column, value) in table.columns.iter().zip(row) {
+        if column.secret {
+            continue;
+        }
+        formatted_row.push(match column.column_type {
+            ColumnType::Date => from_value::<NaiveDate>(value).to_string(),
+            ColumnType::Int => from_value::<i32>(value).to_string(),
+            _ => from_value::<String>(value),
+        });
     }
     formatted_row
 }
 
-pub async fn move_row_to_end(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let new_id = get_max_id(body["table"]).await + 1;
-            change_row_where(body["table"], "id", body["id"], "id", &new_id.to_string()).await;
-            let message = format!("Successfully moved row {} to end.", body["id"]);
-            return json!({"success" : true, "message" : message, "row" : return_row(body["table"], new_id).await, "old_id" : body["id"]}).to_string()
-            ;
-        }
+pub async fn get_row_titles(auth: AuthContext, body: TableRequest) -> Result<RowTitles, ApiError> {
+    let table = editable_table(&auth, &body.table)?;
+    let mut titles: Vec<String> = Vec::new();
+    for title in get_some(table.name, table.title_column).await {
+        titles.push(from_value(title[0].clone()));
     }
-    json!({}).to_string()
+    Ok(RowTitles {
+        table: body.table,
+        titles,
+    })
 }
 
-pub async fn move_row_to_start(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let new_id = get_min_id(body["table"]).await - 1;
-            change_row_where(body["table"], "id", body["id"], "id", &new_id.to_string()).await;
-            let message = format!("Successfully moved row {} to start.", body["id"]);
-            let row = return_row(body["table"], new_id).await;
-            return json!({"success" : true, "message" : message, "row" : row, "old_id" : body["id"]}).to_string();
-        }
-    }
-    json!({}).to_string()
+async fn return_row(table: &str, id: i32) -> Result<Vec<String>, ApiError> {
+    let row = get_where(table, "id", &id.to_string())
+        .await
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::NotFound(format!("Row {} does not exist.", id)))?;
+    Ok(format_row(schema::table(table)?, row))
 }
 
-pub async fn delete_row(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            if body["table"] == "admin" {
-                if session.get("id").await.unwrap() == body["id"] {
-                    return json!({"success" : false, "authorized" : true, "email": queue_delete_email(&mut session).await}).to_string();
-                } else {
-                    return json!({"success" : false, "authorized": false}).to_string();
-                }
-            } else {
-                delete_row_where(body["table"], "id", body["id"]).await;
-                let message = format!("Successfully deleted row {}.", body["id"]);
-                return json!({"success" : true, "message" : message, "id" : body["id"]})
-                    .to_string();
-            }
-        }
-    }
-    json!({}).to_string()
+pub async fn move_row_to_end(auth: AuthContext, body: RowRequest) -> Result<RowResponse, ApiError> {
+    editable_table(&auth, &body.table)?.check_movable()?;
+    check_row_id(&body.id)?;
+    let new_id = get_max_id(&body.table).await + 1;
+    change_row_where(&body.table, "id", &body.id, "id", &new_id.to_string()).await;
+    Ok(RowResponse::Moved {
+        success: true,
+        message: format!("Successfully moved row {} to end.", body.id),
+        row: return_row(&body.table, new_id).await?,
+        old_id: body.id,
+    })
 }
 
-pub async fn add_row(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let names = serde_json::from_str(body["names"]).unwrap();
-            let values = serde_json::from_str(body["values"]).unwrap();
-            if let Err(e) = insert_row(body["table"], names, values).await {
-                return json!({"success" : false, "message" : e}).to_string();
-            } else {
-                let row_id = get_max_id(body["table"]).await;
-                let message = format!("Successfully added row {}.", row_id);
-                let row = return_row(body["table"], row_id).await;
-                return json!({"success" : true, "message" : message, "row" : row}).to_string();
-            }
-        }
-    }
-    json!({}).to_string()
+pub async fn move_row_to_start(
+    auth: AuthContext,
+    body: RowRequest,
+) -> Result<RowResponse, ApiError> {
+    editable_table(&auth, &body.table)?.check_movable()?;
+    check_row_id(&body.id)?;
+    let new_id = get_min_id(&body.table).await - 1;
+    change_row_where(&body.table, "id", &body.id, "id", &new_id.to_string()).await;
+    Ok(RowResponse::Moved {
+        success: true,
+        message: format!("Successfully moved row {} to start.", body.id),
+        row: return_row(&body.table, new_id).await?,
+        old_id: body.id,
+    })
 }
 
-pub async fn change_row(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            if body["table"] == "admin" {
-                if session.get("id").await.unwrap() == body["id"] {
-                    if body["name"] == "email" {
-                        return json!({"success" : false, "authorized" : true, "email": queue_change_email(&mut session, body["value"]).await}).to_string();
-                    }
-                } else {
-                    return json!({"success" : false, "authorized": false}).to_string();
-                }
-            }
-            change_row_where(body["table"], "id", body["id"], body["name"], body["value"]).await;
-            return json!({
-                "success": true,
-                "message": &format!("Successfully updated row {}.", body["id"])
-            })
-            .to_string();
+fn not_own_admin_row() -> ApiError {
+    ApiError::NotAuthorized("You may only modify your own administrator account.".to_string())
+}
+
+pub async fn delete_row(
+    config: &Config,
+    mut auth: AuthContext,
+    body: RowRequest,
+) -> Result<RowResponse, ApiError> {
+    editable_table(&auth, &body.table)?;
+    check_row_id(&body.id)?;
+    if body.table == "admin" {
+        if auth.user_id()? != body.id {
+            return Err(not_own_admin_row());
         }
+        return Ok(RowResponse::EmailQueued {
+            success: false,
+            authorized: true,
+            email: queue_delete_email(config, auth.session()?).await?,
+        });
     }
-    json!({}).to_string()
+    delete_row_where(&body.table, "id", &body.id).await;
+    Ok(RowResponse::Deleted {
+        success: true,
+        message: format!("Successfully deleted row {}.", body.id),
+        id: body.id,
+    })
 }
 
-pub async fn get_gmail_auth_url(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let mut file = File::open("/home/justus/client_secret.json").unwrap();
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).unwrap();
-            let json: Value = serde_json::from_str(&contents).unwrap();
-            return json!({
-                "url": &format!(
-                "https://accounts.google.com/o/oauth2/v2/auth?scope=https://mail.google.com/&include_granted_scopes=true&prompt=consent&redirect_uri=https://www.olmmcc.tk/admin/email/&response_type=code&client_id={}&access_type=offline", 
-                json["client_id"].as_str().unwrap(),
-            )
-            }).to_string();
-        }
+pub async fn add_row(auth: AuthContext, body: AddRowRequest) -> Result<RowResponse, ApiError> {
+    let table = editable_table(&auth, &body.table)?;
+    if body.names.0.len() != body.values.0.len() {
+        return Err(ApiError::invalid_field(
+            "values",
+            "Please provide one value for each column name.",
+        ));
+    }
+    for (name, value) in body.names.0.iter().zip(&body.values.0) {
+        table
+            .editable_column("names", name)?
+            .validate(value)
+            .map_err(|e| ApiError::invalid_field("values", &e))?;
+    }
+    if let Some(column) = table
+        .columns
+        .iter()
+        .find(|t| t.editable && t.required && !body.names.0.iter().any(|n| n == t.name))
+    {
+        return Err(ApiError::invalid_field(
+            "names",
+            &format!("{} is required.", column.display_name),
+        ));
     }
-    json!({"url": ""}).to_string()
+    insert_row(&body.table, body.names.as_strs(), body.values.as_strs())
+        .await
+        .map_err(ApiError::Database)?;
+    let row_id = get_max_id(&body.table).await;
+    Ok(RowResponse::Added {
+        success: true,
+        message: format!("Successfully added row {}.", row_id),
+        row: return_row(&body.table, row_id).await?,
+    })
 }
 
-pub async fn send_gmail_code(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let refresh_token = gmail::get_refresh_token(body["code"]);
-            let email = &session.get("email").await.unwrap();
-            if row_exists("admin", "email", email).await {
-                change_row_where(
-                    "admin",
-                    "email",
-                    email,
-                    "refresh_token",
-                    &refresh_token.await,
-                )
-                .await;
-            } else {
-                insert_row(
-                    "admin",
-                    vec!["email", "refresh_token"],
-                    vec![email, &refresh_token.await],
-                )
-                .await
-                .unwrap();
-            }
+pub async fn change_row(
+    config: &Config,
+    mut auth: AuthContext,
+    body: ChangeRowRequest,
+) -> Result<RowResponse, ApiError> {
+    editable_table(&auth, &body.table)?
+        .editable_column("name", &body.name)?
+        .validate(&body.value)
+        .map_err(|e| ApiError::invalid_field("value", &e))?;
+    check_row_id(&body.id)?;
+    if body.table == "admin" {
+        if auth.user_id()? != body.id {
+            return Err(not_own_admin_row());
+        }
+        if body.name == "email" {
+            return Ok(RowResponse::EmailQueued {
+                success: false,
+                authorized: true,
+                email: queue_change_email(config, auth.session()?, &body.value).await?,
+            });
         }
     }
-    json!({}).to_string()
+    change_row_where(&body.table, "id", &body.id, &body.name, &body.value).await;
+    Ok(RowResponse::Changed {
+        success: true,
+        message: format!("Successfully updated row {}.", body.id),
+    })
 }
 
-pub async fn is_gmail_working(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            if get_refresh_token().await.is_some() {
-                if get_access_token().await.is_some() {
-                    return json!({"working": true}).to_string();
-                }
-            }
-        }
+pub async fn get_gmail_auth_url(
+    config: &Config,
+    auth: AuthContext,
+) -> Result<GmailAuthUrl, ApiError> {
+    auth.require(Permission::MailSend)?;
+    let mut file = File::open(&config.gmail_client_secret)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let json: Value = serde_json::from_str(&contents).map_err(std::io::Error::from)?;
+    let client_id = json["client_id"]
+        .as_str()
+        .ok_or_else(|| ApiError::Mail("The Gmail client secret has no client_id.".to_string()))?;
+    Ok(GmailAuthUrl {
+        url: format!(
+            "https://accounts.google.com/o/oauth2/v2/auth?scope=https://mail.google.com/&include_granted_scopes=true&prompt=consent&redirect_uri={}&response_type=code&client_id={}&access_type=offline",
+            config.gmail_redirect_uri, client_id,
+        ),
+    })
+}
+
+pub async fn send_gmail_code(auth: AuthContext, body: CodeRequest) -> Result<Empty, ApiError> {
+    auth.require(Permission::MailSend)?;
+    let refresh_token = gmail::get_refresh_token(&body.code);
+    let email = auth.email.as_deref().ok_or(ApiError::SessionMissing)?;
+    if row_exists("admin", "email", email).await {
+        change_row_where(
+            "admin",
+            "email",
+            email,
+            "refresh_token",
+            &refresh_token.await,
+        )
+        .await;
+    } else {
+        insert_row(
+            "admin",
+            vec!["email", "refresh_token"],
+            vec![email, &refresh_token.await],
+        )
+        .await
+        .map_err(ApiError::Database)?;
     }
-    json!({"working": false}).to_string()
+    Ok(Empty {})
+}
+
+pub async fn is_gmail_working() -> Result<GmailStatus, ApiError> {
+    Ok(GmailStatus {
+        working: get_access_token().await.is_ok(),
+    })
 }
 
 async fn get_refresh_token() -> Option<MyValue> {
@@ -688,71 +1363,469 @@ async fn get_refresh_token() -> Option<MyValue> {
     return_token
 }
 
-async fn get_access_token() -> Option<String> {
-    let refresh_token = get_refresh_token();
-    gmail::get_access_token(&from_value::<String>(refresh_token.await.unwrap().get())).await
+async fn get_access_token() -> Result<String, ApiError> {
+    let mut refresh_token = get_refresh_token().await.ok_or_else(|| {
+        ApiError::Mail("No administrator has connected a Gmail account.".to_string())
+    })?;
+    gmail::get_access_token(&from_value::<String>(refresh_token.get()))
+        .await
+        .ok_or_else(|| ApiError::Mail("Could not obtain a Gmail access token.".to_string()))
+}
+
+async fn send_mail(recipients: Vec<String>, subject: &str, body: &str) -> Result<(), ApiError> {
+    let access_token = match get_access_token().await {
+        Ok(t) => t,
+        Err(e) => {
+            metrics::EMAILS_SENT.with_label_values(&["failure"]).inc();
+            return Err(e);
+        }
+    };
+    let count = recipients.len();
+    gmail::send_email(recipients, subject, body, &access_token).await;
+    metrics::EMAILS_SENT.with_label_values(&["success"]).inc();
+    tracing::info!(recipients = count, subject, "sent email");
+    Ok(())
+}
+
+/// Sends a code or link to one account's address, at most `emails_per_address_per_hour`
+/// times an hour. `email` must be the address read from the account, not the request.
+async fn send_code_mail(
+    config: &Config,
+    email: &str,
+    subject: &str,
+    body: &str,
+) -> Result<(), ApiError> {
+    rate_limit::check_recipient(&config.rate_limit, email)?;
+    send_mail(vec![email.to_string()], subject, body).await
 }
 
 fn generate_verification_code() -> String {
+    generate_token(16)
+}
+
+fn generate_token(length: usize) -> String {
     let mut rng = thread_rng();
     iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
-        .take(16)
+        .take(length)
         .collect()
 }
 
-pub async fn hash_password(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            if let Some(t) = check_password(body["password"]) {
-                return message(t);
-            }
-            return json!({"hash": hash(body["password"])}).to_string();
+/// Records a single-use login link token for the session in the `login_links` table.
+/// Only a keyed hash of the token is stored.
+async fn store_login_link(config: &Config, session_id: &str) -> Result<String, ApiError> {
+    let token = generate_token(32);
+    let expires = unix_time() + config.code_ttl_minutes * 60;
+    insert_row(
+        "login_links",
+        vec!["token", "session", "expires"],
+        vec![
+            &codes::hash(&config.code_secret, "", "login_link", &token),
+            session_id,
+            &expires.to_string(),
+        ],
+    )
+    .await
+    .map_err(ApiError::Database)?;
+    Ok(token)
+}
+
+/// Deletes the `login_links` rows whose links expired before `now` without being used.
+async fn forget_expired_login_links(now: u64) {
+    for link in get_all_rows("login_links", false).await {
+        if from_value::<u64>(link[2].clone()) < now {
+            delete_row_where(
+                "login_links",
+                "token",
+                &from_value::<String>(link[0].clone()),
+            )
+            .await;
         }
     }
-    json!({}).to_string()
 }
 
-pub async fn verify_account(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("verified").await.unwrap() == "0" {
-            if session.get("verification_code").await.unwrap() == body["code"] {
-                let email = session.get("not_verified_email").await.unwrap();
-                if session.get("not_verified_admin").await.unwrap_or_default() == "1" {
-                    refresh_admin_session(&mut session, "email", email, None).await;
-                } else {
-                    refresh_user_session(&mut session, "email", email, "1").await;
-                }
-                return json!({ "success": true }).to_string();
-            }
-        }
+pub async fn hash_password(body: HashPasswordRequest) -> Result<PasswordHash, ApiError> {
+    if let Some(t) = check_password(&body.password) {
+        return Err(ApiError::invalid_field("password", t));
     }
-    json!({"success": false}).to_string()
+    Ok(PasswordHash {
+        hash: hash(&body.password),
+    })
 }
 
-pub async fn send_email(body: HashMap<&str, &str>) -> String {
-    if let Some(mut session) = Session::from_id(body["session"]).await {
-        if session.get("admin").await.unwrap() == "1" {
-            let mut emails = vec![];
-            if body["recipients"] == "all_users" {
-                for row in get_some("users", "email").await {
-                    emails.push(from_value::<String>(row[0].clone()));
-                }
-                for row in get_some("admin", "email").await {
-                    emails.push(from_value::<String>(row[0].clone()));
-                }
-            } else {
-                emails.push(body["recipient"].to_string());
-            }
-            gmail::send_email(
-                emails,
-                body["subject"],
-                body["body"],
-                get_access_token().await.unwrap().as_str(),
+pub async fn verify_account(
+    config: &Config,
+    mut auth: AuthContext,
+    body: CodeRequest,
+) -> Result<Verified, ApiError> {
+    if auth.verified {
+        return Err(ApiError::validation("This session is already verified."));
+    }
+    let session = auth.session()?;
+    check_code(config, session, "verification_code", &body.code).await?;
+    complete_verification(config, session).await?;
+    Ok(Verified {
+        success: true,
+        session: session.get_id(),
+    })
+}
+
+/// Turns a session waiting for its emailed code into a logged in one. The session is
+/// swapped for a new one, so an id handed out before logging in never becomes logged in.
+async fn complete_verification(config: &Config, session: &mut Session) -> Result<(), ApiError> {
+    let email = get_var(session, "not_verified_email").await?;
+    let admin = is_set(session, "not_verified_admin").await;
+    let totp_verified = is_set(session, "totp_verified").await;
+    let old = std::mem::replace(session, new_session(config).await?);
+    sessions::end(old).await;
+    if totp_verified {
+        session.set("totp_verified", "1".to_string()).await;
+    }
+    if admin {
+        refresh_admin_session(session, "email", email, None).await
+    } else {
+        refresh_user_session(session, "email", email, "1").await
+    }
+}
+
+/// Logs in with the token of an emailed link instead of the code. A new session is
+/// returned in place of the one the link was sent for, so the link works on any device.
+pub async fn verify_link(config: &Config, body: LinkRequest) -> Result<SessionId, ApiError> {
+    let hash = codes::hash(&config.code_secret, "", "login_link", &body.token);
+    let link = get_like("login_links", "token", &hash)
+        .await
+        .into_iter()
+        .next()
+        .ok_or(ApiError::Code(CodeError::Used))?;
+    delete_row_where("login_links", "token", &hash).await;
+    if from_value::<u64>(link[2].clone()) < unix_time() {
+        return Err(ApiError::Code(CodeError::Expired));
+    }
+    let mut session = Session::from_id(&from_value::<String>(link[1].clone()))
+        .await
+        .ok_or(ApiError::SessionMissing)?;
+    if is_set(&mut session, "verified").await {
+        return Err(ApiError::Code(CodeError::Used));
+    }
+    complete_verification(config, &mut session).await?;
+    Ok(SessionId {
+        session: session.get_id(),
+    })
+}
+
+/// The `admin_totp` row of an admin who enrolled an authenticator app.
+async fn totp_row(admin_id: &str) -> Option<Vec<MyValue>> {
+    let row = get_like("admin_totp", "admin_id", admin_id)
+        .await
+        .into_iter()
+        .next()?;
+    Some(row.into_iter().map(MyValue::from).collect())
+}
+
+/// Checks a code from the admin's authenticator app, or one of their recovery codes.
+/// Each time step and each recovery code is only accepted once. Recovery codes are scrypt
+/// hashes, so they keep working if `code_secret` changes.
+async fn check_second_factor(admin_id: &str, code: &str) -> bool {
+    let mut row = match totp_row(admin_id).await {
+        Some(t) => t,
+        None => return false,
+    };
+    let secret = from_value::<String>(row[1].get());
+    let last_step = from_value::<u64>(row[3].get());
+    let correct = match totp::verify(&secret, code, unix_time()) {
+        Some(step) if step > last_step => {
+            change_row_where(
+                "admin_totp",
+                "admin_id",
+                admin_id,
+                "last_step",
+                &step.to_string(),
             )
             .await;
-            return json!({ "success": true }).to_string();
+            true
         }
+        _ => {
+            let recovery_codes = from_value::<String>(row[2].get());
+            let mut recovery_codes: Vec<&str> = recovery_codes.split(',').collect();
+            let before = recovery_codes.len();
+            recovery_codes.retain(|t| !hash_match(code.trim(), t));
+            let used = recovery_codes.len() < before;
+            if used {
+                change_row_where(
+                    "admin_totp",
+                    "admin_id",
+                    admin_id,
+                    "recovery_codes",
+                    &recovery_codes.join(","),
+                )
+                .await;
+            }
+            used
+        }
+    };
+    metrics::CODE_ATTEMPTS
+        .with_label_values(&["totp", if correct { "correct" } else { "wrong" }])
+        .inc();
+    correct
+}
+
+/// How long `/verify_totp` refuses an admin's codes after `max_code_attempts` wrong ones.
+const TOTP_LOCKOUT_SECONDS: u64 = 15 * 60;
+
+lazy_static! {
+    /// Held while a second factor is checked and its failures counted, so parallel
+    /// requests cannot share one count.
+    static ref SECOND_FACTOR_CHECK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// Checks a second factor sent to finish a login. Wrong ones are counted per admin in
+/// `admin_totp`, so starting new logins gives no extra guesses, and after
+/// `max_code_attempts` of them the admin's codes are refused for a while.
+async fn verify_second_factor(config: &Config, admin_id: &str, code: &str) -> Result<(), ApiError> {
+    let _check = SECOND_FACTOR_CHECK.lock().await;
+    let mut row = totp_row(admin_id)
+        .await
+        .ok_or_else(|| ApiError::validation("This account has no authenticator app enrolled."))?;
+    let failures = from_value::<u64>(row[4].get()) as u32;
+    let locked_until = from_value::<u64>(row[5].get());
+    let now = unix_time();
+    if locked_until > now {
+        return Err(ApiError::RateLimited {
+            retry_after: locked_until - now,
+        });
+    }
+    let correct = check_second_factor(admin_id, code).await;
+    if correct && failures == 0 {
+        return Ok(());
+    }
+    let failures = if correct { 0 } else { failures + 1 };
+    let max_attempts = config.rate_limit.max_code_attempts;
+    if failures >= max_attempts {
+        change_row_where(
+            "admin_totp",
+            "admin_id",
+            admin_id,
+            "locked_until",
+            &(now + TOTP_LOCKOUT_SECONDS).to_string(),
+        )
+        .await;
+        change_row_where("admin_totp", "admin_id", admin_id, "failures", "0").await;
+        return Err(ApiError::RateLimited {
+            retry_after: TOTP_LOCKOUT_SECONDS,
+        });
+    }
+    change_row_where(
+        "admin_totp",
+        "admin_id",
+        admin_id,
+        "failures",
+        &failures.to_string(),
+    )
+    .await;
+    if correct {
+        Ok(())
+    } else {
+        Err(ApiError::Code(CodeError::Incorrect {
+            remaining: max_attempts - failures,
+        }))
+    }
+}
+
+/// Finishes an admin login which ended in `totp_required`.
+pub async fn verify_totp(
+    config: &Config,
+    mut auth: AuthContext,
+    body: CodeRequest,
+) -> Result<SessionId, ApiError> {
+    let session = auth.session()?;
+    if !is_set(session, "totp_pending").await {
+        return Err(ApiError::validation(
+            "This session is not waiting for an authenticator code.",
+        ));
+    }
+    let id = get_var(session, "id").await?;
+    verify_second_factor(config, &id, &body.code).await?;
+    session.set("totp_verified", "1".to_string()).await;
+    complete_verification(config, session).await?;
+    Ok(SessionId {
+        session: session.get_id(),
+    })
+}
+
+/// Starts enrolling an authenticator app. The secret is only saved once `/confirm_totp`
+/// receives a code generated from it.
+pub async fn enroll_totp(mut auth: AuthContext) -> Result<TotpEnrollment, ApiError> {
+    if totp_row(auth.user_id()?).await.is_some() {
+        return Err(ApiError::validation(
+            "An authenticator app is already enrolled. Please disable it first.",
+        ));
+    }
+    let email = auth.email.clone().ok_or(ApiError::SessionMissing)?;
+    let secret = totp::generate_secret();
+    auth.session()?.set("totp_enrollment", secret.clone()).await;
+    Ok(TotpEnrollment {
+        uri: totp::provisioning_uri(&secret, &email),
+        secret,
+    })
+}
+
+/// Saves the secret from `/enroll_totp` and returns ten single-use recovery codes, which
+/// are only ever shown here. The admin's other sessions, which never passed the new
+/// second factor, are logged out.
+pub async fn confirm_totp(
+    mut auth: AuthContext,
+    body: CodeRequest,
+) -> Result<RecoveryCodes, ApiError> {
+    let id = auth.user_id()?.to_string();
+    let account = auth.account()?;
+    let session = auth.session()?;
+    let secret = session.get("totp_enrollment").await.unwrap_or_default();
+    if secret.is_empty() {
+        return Err(ApiError::validation(
+            "Please start enrolling an authenticator app first.",
+        ));
+    }
+    let step = totp::verify(&secret, &body.code, unix_time()).ok_or_else(|| {
+        ApiError::invalid_field(
+            "code",
+            "The code is incorrect. Please check the time on your device and try again.",
+        )
+    })?;
+    // Another session may have enrolled since this one called /enroll_totp.
+    if totp_row(&id).await.is_some() {
+        return Err(ApiError::validation(
+            "An authenticator app is already enrolled. Please disable it first.",
+        ));
+    }
+    let recovery_codes: Vec<String> = (0..10).map(|_| generate_token(10)).collect();
+    let hashes: Vec<String> = recovery_codes.iter().map(|t| hash(t)).collect();
+    insert_row(
+        "admin_totp",
+        vec!["admin_id", "secret", "recovery_codes", "last_step"],
+        vec![&id, &secret, &hashes.join(","), &step.to_string()],
+    )
+    .await
+    .map_err(ApiError::Database)?;
+    session
+        .set("totp_enrollment", String::new())
+        .await
+        .set("totp_verified", "1".to_string())
+        .await;
+    sessions::revoke_all(&account, &session.get_id()).await;
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Removes the caller's authenticator app, given a current code or a recovery code.
+pub async fn disable_totp(auth: AuthContext, body: CodeRequest) -> Result<Success, ApiError> {
+    let id = auth.user_id()?;
+    if totp_row(id).await.is_none() {
+        return Err(ApiError::validation("No authenticator app is enrolled."));
+    }
+    if !check_second_factor(id, &body.code).await {
+        return Err(ApiError::invalid_field(
+            "code",
+            "The code is incorrect, please try again.",
+        ));
+    }
+    delete_row_where("admin_totp", "admin_id", id).await;
+    Ok(Success { success: true })
+}
+
+pub async fn send_email(auth: AuthContext, body: SendEmailRequest) -> Result<Success, ApiError> {
+    auth.require(Permission::MailSend)?;
+    let mut emails = vec![];
+    if body.recipients == "all_users" {
+        for row in get_some("users", "email").await {
+            emails.push(from_value::<String>(row[0].clone()));
+        }
+        for row in get_some("admin", "email").await {
+            emails.push(from_value::<String>(row[0].clone()));
+        }
+    } else {
+        emails.push(
+            body.recipient.ok_or_else(|| {
+                ApiError::invalid_field("recipient", "Please provide a recipient.")
+            })?,
+        );
+    }
+    send_mail(emails, &body.subject, &body.body).await?;
+    Ok(Success { success: true })
+}
+
+pub async fn get_permissions(auth: AuthContext) -> Result<Vec<AdminPermissions>, ApiError> {
+    auth.require(Permission::AdminManage)?;
+    Ok(get_all_rows("admin", false)
+        .await
+        .into_iter()
+        .map(|row| AdminPermissions {
+            email: from_value(row[0].clone()),
+            permissions: permissions::from_column(&from_value::<String>(row[5].clone()))
+                .into_iter()
+                .map(|t| t.to_string())
+                .collect(),
+        })
+        .collect())
+}
+
+fn parse_permission(permission: &str) -> Result<Permission, ApiError> {
+    permission
+        .parse()
+        .map_err(|e: String| ApiError::invalid_field("permission", &e))
+}
+
+async fn set_permissions(
+    email: &str,
+    change: impl FnOnce(&mut Vec<Permission>),
+) -> Result<AdminPermissions, ApiError> {
+    let admin = get_where("admin", "email", email)
+        .await
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::NotFound(format!("{} is not an administrator account.", email)))?;
+    let mut permissions = permissions::from_column(&from_value::<String>(admin[5].clone()));
+    change(&mut permissions);
+    change_row_where(
+        "admin",
+        "id",
+        &from_value::<i32>(admin[2].clone()).to_string(),
+        "permissions",
+        &permissions::to_column(&permissions),
+    )
+    .await;
+    Ok(AdminPermissions {
+        email: from_value(admin[0].clone()),
+        permissions: permissions.iter().map(|t| t.to_string()).collect(),
+    })
+}
+
+pub async fn grant_permission(
+    auth: AuthContext,
+    body: PermissionRequest,
+) -> Result<AdminPermissions, ApiError> {
+    auth.require(Permission::AdminManage)?;
+    let permission = parse_permission(&body.permission)?;
+    set_permissions(&body.email.to_lowercase(), |permissions| {
+        if !permissions.contains(&permission) {
+            permissions.push(permission);
+        }
+    })
+    .await
+}
+
+pub async fn revoke_permission(
+    auth: AuthContext,
+    body: PermissionRequest,
+) -> Result<AdminPermissions, ApiError> {
+    auth.require(Permission::AdminManage)?;
+    let permission = parse_permission(&body.permission)?;
+    let email = body.email.to_lowercase();
+    if permission == Permission::AdminManage && auth.email.as_deref() == Some(email.as_str()) {
+        return Err(ApiError::validation(
+            "You cannot revoke admin:manage from yourself. Ask another administrator to do it.",
+        ));
     }
-    json!({ "success": false }).to_string()
+    set_permissions(&email, |permissions| {
+        permissions.retain(|t| *t != permission)
+    })
+    .await
 }
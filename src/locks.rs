@@ -0,0 +1,26 @@
+//! Lock helpers which carry on after a panic while the lock was held. Everything guarded
+//! this way is a cache or a table of counters, which stays usable however far the
+//! panicking thread got.
+
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(t) => t,
+        Err(e) => e.into_inner(),
+    }
+}
+
+pub fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    match lock.read() {
+        Ok(t) => t,
+        Err(e) => e.into_inner(),
+    }
+}
+
+pub fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    match lock.write() {
+        Ok(t) => t,
+        Err(e) => e.into_inner(),
+    }
+}
@@ -0,0 +1,58 @@
+use serde_json::Value;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogConfig, LogFormat};
+
+/// Body fields which must never reach the logs.
+const SECRET_FIELDS: &[&str] = &[
+    "session",
+    "code",
+    "password",
+    "current_password",
+    "hash",
+    "token",
+    "refresh_token",
+    "access_token",
+];
+
+const REDACTED: &str = "[redacted]";
+
+/// Installs the global subscriber. Call once, before serving requests.
+pub fn init(config: &LogConfig) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(&config.level));
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// A random id attached to every log line of one request.
+pub fn request_id() -> String {
+    crate::generate_token(12)
+}
+
+/// Copies a request body with secrets replaced, so it can be logged.
+pub fn redact(body: &Value) -> Value {
+    match body {
+        Value::Object(map) => {
+            // `change_row` sends a password hash as the value of the `password` column.
+            let secret_value = matches!(
+                map.get("name").and_then(Value::as_str),
+                Some(t) if SECRET_FIELDS.contains(&t)
+            );
+            Value::Object(
+                map.iter()
+                    .map(|(k, v)| {
+                        if SECRET_FIELDS.contains(&k.as_str()) || (secret_value && k == "value") {
+                            (k.clone(), Value::from(REDACTED))
+                        } else {
+                            (k.clone(), redact(v))
+                        }
+                    })
+                    .collect(),
+            )
+        }
+        Value::Array(list) => Value::Array(list.iter().map(redact).collect()),
+        t => t.clone(),
+    }
+}
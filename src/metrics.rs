@@ -0,0 +1,55 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "olmmcc_http_requests_total",
+        "Requests handled, by route and status code.",
+        &["route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_SECONDS: HistogramVec = register_histogram_vec!(
+        "olmmcc_http_request_duration_seconds",
+        "Time taken to answer a request, by route.",
+        &["route"]
+    )
+    .unwrap();
+    pub static ref DB_QUERY_SECONDS: HistogramVec = register_histogram_vec!(
+        "olmmcc_db_query_duration_seconds",
+        "Time taken by each mysql helper call, by operation.",
+        &["operation"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap();
+    pub static ref EMAILS_SENT: IntCounterVec = register_int_counter_vec!(
+        "olmmcc_emails_sent_total",
+        "Emails handed to Gmail, by outcome (success or failure).",
+        &["outcome"]
+    )
+    .unwrap();
+    /// Unexpired sessions in the store, counted after each sweep.
+    pub static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "olmmcc_active_sessions",
+        "Unexpired sessions in the session store as of the last sweep."
+    )
+    .unwrap();
+    pub static ref CODE_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "olmmcc_verification_code_attempts_total",
+        "Verification codes submitted, by purpose and outcome (correct, wrong or expired).",
+        &["purpose", "outcome"]
+    )
+    .unwrap();
+}
+
+/// Every registered metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(error = %e, "could not encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
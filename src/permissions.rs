@@ -0,0 +1,99 @@
+use serde::{Serialize, Serializer};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Something an administrator may be allowed to do, stored in the `permissions`
+/// column of the `admin` table as a comma separated list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Edit the `calendar` table.
+    CalendarWrite,
+    /// Edit the `songs` and `articles` tables.
+    SongsWrite,
+    /// Send email to users and connect the Gmail account.
+    MailSend,
+    /// Edit every table and grant or revoke permissions. Implies all others.
+    AdminManage,
+}
+
+pub const ALL: &[Permission] = &[
+    Permission::CalendarWrite,
+    Permission::SongsWrite,
+    Permission::MailSend,
+    Permission::AdminManage,
+];
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::CalendarWrite => "calendar:write",
+            Permission::SongsWrite => "songs:write",
+            Permission::MailSend => "mail:send",
+            Permission::AdminManage => "admin:manage",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Permission {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL.iter()
+            .copied()
+            .find(|t| t.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown permission {}. Please use one of: {}.",
+                    s,
+                    to_column(ALL)
+                )
+            })
+    }
+}
+
+/// Reads the `permissions` column, skipping anything unrecognised.
+pub fn from_column(column: &str) -> Vec<Permission> {
+    column
+        .split(',')
+        .filter_map(|t| t.trim().parse().ok())
+        .collect()
+}
+
+pub fn to_column(permissions: &[Permission]) -> String {
+    permissions
+        .iter()
+        .map(|t| t.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_column_skips_unknown_permissions_and_whitespace() {
+        assert_eq!(
+            from_column("calendar:write, mail:send,,root,SONGS:WRITE"),
+            vec![Permission::CalendarWrite, Permission::MailSend]
+        );
+        assert!(from_column("").is_empty());
+    }
+
+    #[test]
+    fn from_column_reads_what_to_column_writes() {
+        assert_eq!(from_column(&to_column(ALL)), ALL.to_vec());
+    }
+}
@@ -0,0 +1,136 @@
+use lazy_static::lazy_static;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+use crate::error::ApiError;
+use crate::locks;
+use crate::router::RateLimit;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// Stale windows are dropped once the table grows past this many keys.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Window {
+    start: Instant,
+    period: Duration,
+    count: u32,
+}
+
+lazy_static! {
+    /// Emails sent to each address, counted where the mail is sent so the key is the real
+    /// recipient rather than whatever the caller typed.
+    static ref RECIPIENTS: RateLimiter = RateLimiter::new();
+}
+
+/// Fixed-window counters shared by every request, keyed by e.g. `email-ip:1.2.3.4`.
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+
+    /// Counts a hit on `key`, or returns how long to wait if `limit` is already used up.
+    pub fn hit(&self, key: String, limit: u32, period: Duration) -> Result<(), Duration> {
+        let mut windows = locks::lock(&self.windows);
+        let now = Instant::now();
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, t| now.duration_since(t.start) < t.period);
+        }
+        let window = windows.entry(key).or_insert(Window {
+            start: now,
+            period,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= window.period {
+            window.start = now;
+            window.count = 0;
+        }
+        if window.count >= limit {
+            return Err(window.period - now.duration_since(window.start));
+        }
+        window.count += 1;
+        Ok(())
+    }
+
+    /// Applies the limits of a route's class to the caller's address.
+    pub fn check(
+        &self,
+        config: &RateLimitConfig,
+        class: RateLimit,
+        ip: IpAddr,
+    ) -> Result<(), ApiError> {
+        let result = match class {
+            RateLimit::Standard => self.hit(
+                format!("standard-ip:{}", ip),
+                config.requests_per_minute,
+                MINUTE,
+            ),
+            RateLimit::Code => self.hit(format!("code-ip:{}", ip), config.codes_per_minute, MINUTE),
+            RateLimit::Email => self.hit(
+                format!("email-ip:{}", ip),
+                config.emails_per_ip_per_hour,
+                HOUR,
+            ),
+        };
+        result.map_err(rate_limited)
+    }
+}
+
+fn rate_limited(wait: Duration) -> ApiError {
+    ApiError::RateLimited {
+        retry_after: wait.as_secs() + 1,
+    }
+}
+
+/// Counts a code or link emailed to `address`, refusing it once the address had
+/// `emails_per_address_per_hour` of them.
+pub fn check_recipient(config: &RateLimitConfig, address: &str) -> Result<(), ApiError> {
+    RECIPIENTS
+        .hit(
+            format!("email-address:{}", address.to_lowercase()),
+            config.emails_per_address_per_hour,
+            HOUR,
+        )
+        .map_err(rate_limited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_refuses_once_the_limit_is_used_up() {
+        let limiter = RateLimiter::new();
+        for _ in 0..3 {
+            assert!(limiter.hit("a".to_string(), 3, MINUTE).is_ok());
+        }
+        let wait = limiter.hit("a".to_string(), 3, MINUTE).unwrap_err();
+        assert!(wait > Duration::from_secs(0) && wait <= MINUTE);
+        assert!(limiter.hit("b".to_string(), 3, MINUTE).is_ok());
+    }
+
+    #[test]
+    fn hit_starts_a_new_window_after_the_period() {
+        let limiter = RateLimiter::new();
+        let period = Duration::from_millis(20);
+        assert!(limiter.hit("a".to_string(), 1, period).is_ok());
+        assert!(limiter.hit("a".to_string(), 1, period).is_err());
+        std::thread::sleep(period);
+        assert!(limiter.hit("a".to_string(), 1, period).is_ok());
+    }
+
+    #[test]
+    fn a_limit_of_zero_refuses_everything() {
+        assert!(RateLimiter::new().hit("a".to_string(), 0, MINUTE).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Describes why a request body could not be turned into a request struct.
+#[derive(Debug, Default)]
+pub struct BodyError {
+    pub message: String,
+    pub missing: Vec<&'static str>,
+    pub invalid: Vec<&'static str>,
+}
+
+/// A request body which can be checked field by field before it is deserialized.
+pub trait Request: DeserializeOwned {
+    fn check(body: &Value) -> BodyError;
+}
+
+fn field_is_valid<T: DeserializeOwned>(value: &Value) -> bool {
+    serde_json::from_value::<T>(value.clone()).is_ok()
+}
+
+/// Converts numbers and booleans into the strings a form body would have carried.
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(t) => Some(t.clone()),
+        Value::Number(t) => Some(t.to_string()),
+        Value::Bool(t) => Some(t.to_string()),
+        _ => None,
+    }
+}
+
+/// Deserializes `body` into `T`, listing every missing or invalid field on failure.
+///
+/// Top level numbers and booleans are accepted wherever a string is expected, so JSON
+/// and form bodies deserialize into the same structs.
+pub fn parse<T: Request>(body: &Value) -> Result<T, BodyError> {
+    let body = match body {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| match v {
+                    Value::Number(_) | Value::Bool(_) => {
+                        (k.clone(), scalar_to_string(v).map(Value::String).unwrap())
+                    }
+                    _ => (k.clone(), v.clone()),
+                })
+                .collect(),
+        ),
+        _ => {
+            return Err(BodyError {
+                message: "The request body must be an object.".to_string(),
+                ..Default::default()
+            })
+        }
+    };
+    let mut error = T::check(&body);
+    if error.missing.is_empty() && error.invalid.is_empty() {
+        match serde_json::from_value(body) {
+            Ok(t) => return Ok(t),
+            Err(e) => error.message = e.to_string(),
+        }
+    } else {
+        error.message = "The request body is missing or has invalid fields.".to_string();
+    }
+    Err(error)
+}
+
+/// A list of strings, sent either as a JSON array or as a string containing one.
+#[derive(Debug)]
+pub struct StringList(pub Vec<String>);
+
+impl StringList {
+    pub fn as_strs(&self) -> Vec<&str> {
+        self.0.iter().map(String::as_str).collect()
+    }
+}
+
+impl<'de> Deserialize<'de> for StringList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let list = match Value::deserialize(deserializer)? {
+            Value::Array(list) => list,
+            Value::String(t) => serde_json::from_str(&t).map_err(de::Error::custom)?,
+            _ => return Err(de::Error::custom("expected a list")),
+        };
+        list.iter()
+            .map(scalar_to_string)
+            .collect::<Option<Vec<String>>>()
+            .map(StringList)
+            .ok_or_else(|| de::Error::custom("expected a list of strings"))
+    }
+}
+
+macro_rules! request {
+    (
+        $(#[$meta:meta])*
+        $name:ident {
+            $($field:ident: $ty:ty),* $(,)?
+        }
+        $(optional {
+            $($opt_field:ident: $opt_ty:ty),* $(,)?
+        })?
+    ) => {
+        $(#[$meta])*
+        #[derive(Deserialize, Debug)]
+        pub struct $name {
+            $(pub $field: $ty,)*
+            $($(#[serde(default)] pub $opt_field: Option<$opt_ty>,)*)?
+        }
+        impl Request for $name {
+            fn check(body: &Value) -> BodyError {
+                let mut error = BodyError::default();
+                $(match body.get(stringify!($field)) {
+                    None => error.missing.push(stringify!($field)),
+                    Some(value) => {
+                        if !field_is_valid::<$ty>(value) {
+                            error.invalid.push(stringify!($field));
+                        }
+                    }
+                })*
+                $($(if let Some(value) = body.get(stringify!($opt_field)) {
+                    if !value.is_null() && !field_is_valid::<$opt_ty>(value) {
+                        error.invalid.push(stringify!($opt_field));
+                    }
+                })*)?
+                error
+            }
+        }
+    };
+}
+
+request! {
+    /// A request which submits an emailed verification code.
+    CodeRequest {
+        code: String,
+    }
+}
+
+request! {
+    /// A request which submits the token of an emailed login link.
+    LinkRequest {
+        token: String,
+    }
+}
+
+request! {
+    /// A request which only carries an email address, such as `/signup` or `/login`.
+    EmailRequest {
+        email: String,
+    }
+}
+
+request! {
+    /// Logs in with a password, used by `/admin_login` and `/password_login`.
+    PasswordLoginRequest {
+        email: String,
+        password: String,
+    }
+}
+
+request! {
+    SetPasswordRequest {
+        password: String,
+    }
+    optional {
+        current_password: String,
+    }
+}
+
+request! {
+    ChangePasswordRequest {
+        current_password: String,
+        password: String,
+    }
+}
+
+request! {
+    /// Names one of the caller's sessions by the `handle` from `/list_sessions`.
+    RevokeSessionRequest {
+        handle: String,
+    }
+}
+
+request! {
+    ResetPasswordRequest {
+        code: String,
+        password: String,
+    }
+}
+
+request! {
+    HashPasswordRequest {
+        password: String,
+    }
+}
+
+request! {
+    CalendarEventsRequest {
+        year_month: String,
+    }
+}
+
+request! {
+    GetAccountRequest {
+        details: String,
+    }
+}
+
+request! {
+    ChangeSubscriptionRequest {
+        subscription: String,
+    }
+}
+
+request! {
+    SendChangeEmailRequest {
+        email: String,
+    }
+}
+
+request! {
+    /// A request which targets a whole table.
+    TableRequest {
+        table: String,
+    }
+}
+
+request! {
+    /// A request which targets a single row of a table.
+    RowRequest {
+        table: String,
+        id: String,
+    }
+}
+
+request! {
+    AddRowRequest {
+        table: String,
+        names: StringList,
+        values: StringList,
+    }
+}
+
+request! {
+    ChangeRowRequest {
+        table: String,
+        id: String,
+        name: String,
+        value: String,
+    }
+}
+
+request! {
+    SendEmailRequest {
+        recipients: String,
+        subject: String,
+        body: String,
+    }
+    optional {
+        recipient: String,
+    }
+}
+
+request! {
+    /// Grants or revokes one permission of the admin with `email`.
+    PermissionRequest {
+        email: String,
+        permission: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_lists_missing_and_invalid_fields() {
+        let error = parse::<PasswordLoginRequest>(&json!({ "email": ["a"] })).unwrap_err();
+        assert_eq!(error.missing, vec!["password"]);
+        assert_eq!(error.invalid, vec!["email"]);
+    }
+
+    #[test]
+    fn parse_checks_optional_fields_only_when_present() {
+        let request = parse::<SetPasswordRequest>(&json!({ "password": "hunter22" })).unwrap();
+        assert_eq!(request.current_password, None);
+        let error = parse::<SetPasswordRequest>(&json!({
+            "password": "hunter22",
+            "current_password": {},
+        }))
+        .unwrap_err();
+        assert!(error.missing.is_empty());
+        assert_eq!(error.invalid, vec!["current_password"]);
+    }
+
+    #[test]
+    fn parse_accepts_numbers_and_booleans_as_strings() {
+        let request = parse::<CodeRequest>(&json!({ "code": 123456 })).unwrap();
+        assert_eq!(request.code, "123456");
+    }
+
+    #[test]
+    fn parse_rejects_bodies_which_are_not_objects() {
+        let error = parse::<CodeRequest>(&json!(["code"])).unwrap_err();
+        assert!(error.missing.is_empty() && error.invalid.is_empty());
+        assert_eq!(error.message, "The request body must be an object.");
+    }
+
+    #[test]
+    fn string_lists_may_be_sent_as_json_strings() {
+        let request = parse::<AddRowRequest>(&json!({
+            "table": "songs",
+            "names": "[\"name\",\"link\"]",
+            "values": ["Song", 1],
+        }))
+        .unwrap();
+        assert_eq!(request.names.as_strs(), vec!["name", "link"]);
+        assert_eq!(request.values.as_strs(), vec!["Song", "1"]);
+    }
+}
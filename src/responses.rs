@@ -0,0 +1,178 @@
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+use crate::schema::ColumnType;
+
+#[derive(Serialize)]
+pub struct Message {
+    pub message: String,
+}
+
+impl Message {
+    pub fn new(message: &str) -> Self {
+        Message {
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Success {
+    pub success: bool,
+}
+
+#[derive(Serialize)]
+pub struct Empty {}
+
+#[derive(Serialize)]
+pub struct Song {
+    pub name: String,
+    pub link: String,
+    pub role: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct SongArticle {
+    pub title: String,
+    pub text: String,
+    pub songs: Vec<Song>,
+}
+
+#[derive(Serialize)]
+pub struct ImageList {
+    pub images: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CalendarEvent {
+    pub id: i64,
+    pub title: String,
+    pub date: String,
+    pub start_time: String,
+    pub end_time: String,
+    pub notes: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginEmail {
+    pub session: String,
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionId {
+    pub session: String,
+}
+
+/// Sent once a session is logged in, with the new id which replaces the caller's.
+#[derive(Serialize)]
+pub struct Verified {
+    pub success: bool,
+    pub session: String,
+}
+
+#[derive(Serialize)]
+pub struct PasswordHash {
+    pub hash: String,
+}
+
+#[derive(Serialize)]
+pub struct QueuedEmail {
+    pub success: bool,
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct DatabaseTable {
+    pub success: bool,
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<Vec<String>>,
+    pub types: Vec<ColumnType>,
+}
+
+#[derive(Serialize)]
+pub struct RowTitles {
+    pub table: String,
+    pub titles: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum RowResponse {
+    Moved {
+        success: bool,
+        message: String,
+        row: Vec<String>,
+        old_id: String,
+    },
+    Added {
+        success: bool,
+        message: String,
+        row: Vec<String>,
+    },
+    Deleted {
+        success: bool,
+        message: String,
+        id: String,
+    },
+    Changed {
+        success: bool,
+        message: String,
+    },
+    EmailQueued {
+        success: bool,
+        authorized: bool,
+        email: String,
+    },
+}
+
+#[derive(Serialize)]
+pub struct GmailAuthUrl {
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub handle: String,
+    pub current: bool,
+    pub ip: String,
+    pub device: String,
+    pub created: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct GmailStatus {
+    pub working: bool,
+}
+
+#[derive(Serialize)]
+pub struct AdminPermissions {
+    pub email: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct DependencyStatus {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, DependencyStatus>,
+}
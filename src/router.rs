@@ -0,0 +1,140 @@
+use hyper::Method;
+use serde_json::Value;
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::auth::AuthContext;
+use crate::config::Config;
+use crate::error::ApiError;
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<String, ApiError>> + Send + 'a>>;
+pub type Handler = for<'a> fn(&'a Config, AuthContext, &'a Value) -> HandlerFuture<'a>;
+
+/// Who may call a route. Each role includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Public,
+    User,
+    Admin,
+}
+
+/// How strictly a route is rate limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    Standard,
+    /// Sends an email to an address supplied by the caller.
+    Email,
+    /// Checks a secret such as a verification code or password.
+    Code,
+}
+
+pub const GET_POST: &[Method] = &[Method::GET, Method::POST];
+pub const POST: &[Method] = &[Method::POST];
+
+pub struct Route {
+    pub path: &'static str,
+    pub role: Role,
+    pub methods: &'static [Method],
+    pub rate_limit: RateLimit,
+    handler: Handler,
+}
+
+impl Route {
+    pub fn allows(&self, method: &Method) -> bool {
+        self.methods.contains(method)
+    }
+    /// The value of the `Allow` header sent when a method is rejected.
+    pub fn allow_header(&self) -> String {
+        self.methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+    /// Runs the handler if the caller holds the route's role.
+    pub async fn call(
+        &self,
+        config: &Config,
+        auth: AuthContext,
+        body: &Value,
+    ) -> Result<String, ApiError> {
+        auth.authorize(self.role)?;
+        (self.handler)(config, auth, body).await
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<24} {:<10} {:<8} {:?}",
+            self.path,
+            self.allow_header(),
+            format!("{:?}", self.role),
+            self.rate_limit
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+    pub fn add(
+        &mut self,
+        path: &'static str,
+        role: Role,
+        methods: &'static [Method],
+        rate_limit: RateLimit,
+        handler: Handler,
+    ) -> &mut Self {
+        self.routes.push(Route {
+            path,
+            role,
+            methods,
+            rate_limit,
+            handler,
+        });
+        self
+    }
+    /// Finds the route for a url, ignoring any query string and trailing slash.
+    pub fn find(&self, url: &str) -> Option<&Route> {
+        let path = normalize_path(url);
+        self.routes.iter().find(|route| route.path == path)
+    }
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+}
+
+/// Strips the query string and any trailing slashes from a url.
+pub fn normalize_path(url: &str) -> &str {
+    let path = url.split(&['?', '#'][..]).next().unwrap_or_default();
+    match path.trim_end_matches('/') {
+        "" => "/",
+        t => t,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_strips_queries_fragments_and_trailing_slashes() {
+        assert_eq!(normalize_path("/login"), "/login");
+        assert_eq!(normalize_path("/login/"), "/login");
+        assert_eq!(normalize_path("/login//?email=a"), "/login");
+        assert_eq!(normalize_path("/get_songs#top"), "/get_songs");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("?session=a"), "/");
+    }
+}
@@ -0,0 +1,296 @@
+use chrono::{NaiveDate, NaiveTime};
+use serde::{Serialize, Serializer};
+
+use crate::account_validation::check_subscription;
+use crate::error::ApiError;
+use crate::permissions::Permission;
+
+/// How a column's values are sent to and from the admin UI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+    Int,
+    Text,
+    /// `YYYY-MM-DD`.
+    Date,
+    /// `HH:MM` or `HH:MM:SS`.
+    Time,
+    Url,
+    Email,
+}
+
+impl ColumnType {
+    fn describe(self) -> &'static str {
+        match self {
+            ColumnType::Int => "a whole number",
+            ColumnType::Text => "text",
+            ColumnType::Date => "a date like 2020-12-31",
+            ColumnType::Time => "a time like 19:30",
+            ColumnType::Url => "a link starting with http:// or https://",
+            ColumnType::Email => "an email address",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Column {
+    pub name: &'static str,
+    pub display_name: &'static str,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+    /// Whether `add_row` and `change_row` may set this column.
+    pub editable: bool,
+    /// Whether `add_row` must be given a non-empty value.
+    pub required: bool,
+    pub max_length: Option<usize>,
+    /// Never sent to the admin UI, neither in the schema nor in rows.
+    #[serde(skip)]
+    pub secret: bool,
+    /// An extra check beyond the column type, returning the error message.
+    #[serde(skip)]
+    pub rule: Option<fn(&str) -> Option<&str>>,
+}
+
+#[derive(Serialize)]
+pub struct Table {
+    pub name: &'static str,
+    pub display_name: &'static str,
+    /// Needed to view or change any row of the table.
+    pub permission: Permission,
+    /// The column `/get_row_titles` lists.
+    pub title_column: &'static str,
+    /// Whether `/move_row_to_end` and `/move_row_to_start` may change row ids. False
+    /// where other tables refer to rows by id.
+    pub movable: bool,
+    /// In the same order as the columns of the database table.
+    #[serde(serialize_with = "serialize_columns")]
+    pub columns: &'static [Column],
+}
+
+fn serialize_columns<S: Serializer>(columns: &[Column], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(columns.iter().filter(|t| !t.secret))
+}
+
+const fn column(name: &'static str, display_name: &'static str, column_type: ColumnType) -> Column {
+    Column {
+        name,
+        display_name,
+        column_type,
+        editable: true,
+        required: false,
+        max_length: None,
+        secret: false,
+        rule: None,
+    }
+}
+
+const fn id() -> Column {
+    Column {
+        editable: false,
+        ..column("id", "ID", ColumnType::Int)
+    }
+}
+
+const fn required(column: Column) -> Column {
+    Column {
+        required: true,
+        ..column
+    }
+}
+
+const fn max_length(length: usize, column: Column) -> Column {
+    Column {
+        max_length: Some(length),
+        ..column
+    }
+}
+
+const fn read_only(column: Column) -> Column {
+    Column {
+        editable: false,
+        ..column
+    }
+}
+
+const fn secret(column: Column) -> Column {
+    Column {
+        editable: false,
+        secret: true,
+        ..column
+    }
+}
+
+/// Every table the admin UI may touch. Anything else is rejected.
+pub const TABLES: &[Table] = &[
+    Table {
+        name: "articles",
+        display_name: "Articles",
+        permission: Permission::SongsWrite,
+        title_column: "title",
+        movable: true,
+        columns: &[
+            id(),
+            required(max_length(255, column("title", "Title", ColumnType::Text))),
+            column("text", "Text", ColumnType::Text),
+            required(column("expiry_date", "Expiry Date", ColumnType::Date)),
+        ],
+    },
+    Table {
+        name: "songs",
+        display_name: "Songs",
+        permission: Permission::SongsWrite,
+        title_column: "name",
+        movable: true,
+        columns: &[
+            id(),
+            required(max_length(255, column("name", "Name", ColumnType::Text))),
+            required(max_length(255, column("link", "Link", ColumnType::Url))),
+            max_length(255, column("role", "Role", ColumnType::Text)),
+            required(max_length(
+                255,
+                column("article", "Article", ColumnType::Text),
+            )),
+        ],
+    },
+    Table {
+        name: "calendar",
+        display_name: "Calendar",
+        permission: Permission::CalendarWrite,
+        title_column: "title",
+        movable: true,
+        columns: &[
+            id(),
+            required(max_length(255, column("title", "Title", ColumnType::Text))),
+            required(column("date", "Date", ColumnType::Date)),
+            column("start_time", "Start Time", ColumnType::Time),
+            column("end_time", "End Time", ColumnType::Time),
+            max_length(255, column("location", "Location", ColumnType::Text)),
+            column("notes", "Notes", ColumnType::Text),
+        ],
+    },
+    Table {
+        name: "users",
+        display_name: "Users",
+        permission: Permission::AdminManage,
+        title_column: "email",
+        movable: false,
+        columns: &[
+            required(max_length(64, column("email", "Email", ColumnType::Email))),
+            id(),
+            Column {
+                rule: Some(check_subscription),
+                ..column("subscription_policy", "Subscription", ColumnType::Int)
+            },
+            // Only set by the user through /set_password or /reset_password.
+            secret(column("password", "Password Hash", ColumnType::Text)),
+        ],
+    },
+    Table {
+        name: "admin",
+        display_name: "Administrators",
+        permission: Permission::AdminManage,
+        title_column: "email",
+        movable: false,
+        columns: &[
+            required(max_length(64, column("email", "Email", ColumnType::Email))),
+            // Only set by the admin through /change_admin_password or /reset_admin_password.
+            secret(column("password", "Password Hash", ColumnType::Text)),
+            id(),
+            Column {
+                rule: Some(check_subscription),
+                ..column("subscription_policy", "Subscription", ColumnType::Int)
+            },
+            secret(column("refresh_token", "Gmail Token", ColumnType::Text)),
+            // Changed through /grant_permission and /revoke_permission.
+            read_only(column("permissions", "Permissions", ColumnType::Text)),
+        ],
+    },
+];
+
+/// Looks up a table the admin UI may touch.
+pub fn table(name: &str) -> Result<&'static Table, ApiError> {
+    TABLES.iter().find(|t| t.name == name).ok_or_else(|| {
+        ApiError::invalid_field("table", &format!("{} is not an editable table.", name))
+    })
+}
+
+impl Table {
+    /// Checks the table's rows may be given new ids.
+    pub fn check_movable(&self) -> Result<(), ApiError> {
+        if self.movable {
+            Ok(())
+        } else {
+            Err(ApiError::invalid_field(
+                "table",
+                &format!("Rows of {} cannot be moved.", self.name),
+            ))
+        }
+    }
+
+    /// The columns the admin UI is shown, in database order.
+    pub fn visible_columns(&self) -> impl Iterator<Item = &Column> {
+        self.columns.iter().filter(|t| !t.secret)
+    }
+
+    /// Looks up a column which may be set, `field` naming the request field it came from.
+    pub fn editable_column(
+        &'static self,
+        field: &'static str,
+        name: &str,
+    ) -> Result<&'static Column, ApiError> {
+        match self.columns.iter().find(|t| t.name == name) {
+            Some(column) if column.editable => Ok(column),
+            Some(_) => Err(ApiError::invalid_field(
+                field,
+                &format!("The {} column of {} cannot be changed.", name, self.name),
+            )),
+            None => Err(ApiError::invalid_field(
+                field,
+                &format!("{} has no column named {}.", self.name, name),
+            )),
+        }
+    }
+}
+
+impl Column {
+    /// Checks a new value against the column's type and rules.
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        if value.is_empty() {
+            return if self.required {
+                Err(format!("{} is required.", self.display_name))
+            } else {
+                Ok(())
+            };
+        }
+        if let Some(max) = self.max_length {
+            if value.chars().count() > max {
+                return Err(format!(
+                    "{} must be at most {} characters long.",
+                    self.display_name, max
+                ));
+            }
+        }
+        let valid = match self.column_type {
+            ColumnType::Int => value.parse::<i64>().is_ok(),
+            ColumnType::Text => true,
+            ColumnType::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            ColumnType::Time => {
+                NaiveTime::parse_from_str(value, "%H:%M").is_ok()
+                    || NaiveTime::parse_from_str(value, "%H:%M:%S").is_ok()
+            }
+            ColumnType::Url => value.starts_with("https://") || value.starts_with("http://"),
+            ColumnType::Email => value.contains('@'),
+        };
+        if !valid {
+            return Err(format!(
+                "{} must be {}.",
+                self.display_name,
+                self.column_type.describe()
+            ));
+        }
+        match self.rule.and_then(|rule| rule(value)) {
+            Some(message) => Err(message.to_string()),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::config::Config;
+use crate::db::*;
+use crate::locks;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where session variables are kept. Sessions past their expiry time must read as missing
+/// even before `sweep` removes them.
+pub trait SessionStore: Send + Sync {
+    /// Stores an empty session which expires at unix time `expires`.
+    fn create<'a>(&'a self, id: &'a str, expires: u64) -> StoreFuture<'a, Result<(), String>>;
+    fn exists<'a>(&'a self, id: &'a str) -> StoreFuture<'a, bool>;
+    fn get<'a>(&'a self, id: &'a str, key: &'a str) -> StoreFuture<'a, Option<String>>;
+    fn set<'a>(&'a self, id: &'a str, key: &'a str, value: String) -> StoreFuture<'a, ()>;
+    /// Removes every variable but keeps the session.
+    fn clear<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()>;
+    fn delete<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()>;
+    /// Removes the sessions which expired before unix time `now`, returning their ids.
+    fn sweep(&self, now: u64) -> StoreFuture<'_, Vec<String>>;
+    /// How many sessions have not expired by unix time `now`.
+    fn count(&self, now: u64) -> StoreFuture<'_, usize>;
+}
+
+/// Which [`SessionStore`] the server uses, set by `session_store` in the configuration.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// Kept in the server's memory, so sessions end on restart and are not shared
+    /// between servers.
+    Memory,
+    /// Kept in the `session_data` table.
+    Mysql,
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StoreKind::Memory),
+            "mysql" => Ok(StoreKind::Mysql),
+            _ => Err("expected memory or mysql".to_string()),
+        }
+    }
+}
+
+lazy_static! {
+    static ref STORE: RwLock<Arc<dyn SessionStore>> = RwLock::new(Arc::new(MemoryStore::default()));
+}
+
+/// Replaces the store every [`Session`] is read from. The memory store is used until this
+/// is called.
+pub fn set_store(store: Arc<dyn SessionStore>) {
+    *locks::write(&STORE) = store;
+}
+
+/// Uses the store named in the configuration.
+pub fn init(config: &Config) {
+    set_store(match config.session_store {
+        StoreKind::Memory => Arc::new(MemoryStore::default()),
+        StoreKind::Mysql => Arc::new(MysqlStore),
+    });
+}
+
+fn store() -> Arc<dyn SessionStore> {
+    locks::read(&STORE).clone()
+}
+
+/// Removes expired sessions and their `account_sessions` rows, returning how many there were.
+/// Also deletes unused login links which have expired, and sets the active sessions gauge to
+/// the number of sessions left.
+pub async fn sweep() -> usize {
+    let store = store();
+    let now = crate::unix_time();
+    let expired = store.sweep(now).await;
+    for id in &expired {
+        crate::sessions::forget(id).await;
+    }
+    crate::forget_expired_login_links(now).await;
+    crate::metrics::ACTIVE_SESSIONS.set(store.count(now).await as i64);
+    expired.len()
+}
+
+/// A handle on one stored session.
+pub struct Session {
+    id: String,
+    store: Arc<dyn SessionStore>,
+}
+
+impl Session {
+    pub async fn new(lifetime_days: u64, id_length: u64) -> Result<Session, String> {
+        let session = Session {
+            id: crate::generate_token(id_length as usize),
+            store: store(),
+        };
+        let expires = crate::unix_time() + lifetime_days * 24 * 60 * 60;
+        session.store.create(&session.id, expires).await?;
+        Ok(session)
+    }
+
+    /// Opens an existing session, or `None` if it was deleted or has expired.
+    pub async fn from_id(id: &str) -> Option<Session> {
+        let store = store();
+        if store.exists(id).await {
+            Some(Session {
+                id: id.to_string(),
+                store,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    pub async fn get(&mut self, key: &str) -> Option<String> {
+        self.store.get(&self.id, key).await
+    }
+
+    pub async fn set(&mut self, key: &str, value: String) -> &mut Session {
+        self.store.set(&self.id, key, value).await;
+        self
+    }
+
+    pub async fn clear(&mut self) {
+        self.store.clear(&self.id).await
+    }
+
+    pub async fn delete(&mut self) {
+        self.store.delete(&self.id).await
+    }
+}
+
+struct StoredSession {
+    expires: u64,
+    values: HashMap<String, String>,
+}
+
+/// Sessions held in a map in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+impl MemoryStore {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, StoredSession>> {
+        locks::lock(&self.sessions)
+    }
+
+    /// Runs `f` on the session `id` if it exists and has not expired.
+    fn with<T>(&self, id: &str, f: impl FnOnce(&mut StoredSession) -> T) -> Option<T> {
+        let mut sessions = self.lock();
+        match sessions.get_mut(id) {
+            Some(session) if session.expires > crate::unix_time() => Some(f(session)),
+            _ => None,
+        }
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn create<'a>(&'a self, id: &'a str, expires: u64) -> StoreFuture<'a, Result<(), String>> {
+        let mut sessions = self.lock();
+        sessions.insert(
+            id.to_string(),
+            StoredSession {
+                expires,
+                values: HashMap::new(),
+            },
+        );
+        Box::pin(async { Ok(()) })
+    }
+
+    fn exists<'a>(&'a self, id: &'a str) -> StoreFuture<'a, bool> {
+        let exists = self.with(id, |_| ()).is_some();
+        Box::pin(async move { exists })
+    }
+
+    fn get<'a>(&'a self, id: &'a str, key: &'a str) -> StoreFuture<'a, Option<String>> {
+        let value = self.with(id, |t| t.values.get(key).cloned()).flatten();
+        Box::pin(async move { value })
+    }
+
+    fn set<'a>(&'a self, id: &'a str, key: &'a str, value: String) -> StoreFuture<'a, ()> {
+        self.with(id, |t| t.values.insert(key.to_string(), value));
+        Box::pin(async {})
+    }
+
+    fn clear<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()> {
+        self.with(id, |t| t.values.clear());
+        Box::pin(async {})
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()> {
+        let mut sessions = self.lock();
+        sessions.remove(id);
+        Box::pin(async {})
+    }
+
+    fn sweep(&self, now: u64) -> StoreFuture<'_, Vec<String>> {
+        let mut sessions = self.lock();
+        let expired: Vec<String> = sessions
+            .iter()
+            .filter(|(_, t)| t.expires <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            sessions.remove(id);
+        }
+        Box::pin(async move { expired })
+    }
+
+    fn count(&self, now: u64) -> StoreFuture<'_, usize> {
+        let count = self.lock().values().filter(|t| t.expires > now).count();
+        Box::pin(async move { count })
+    }
+}
+
+/// Sessions held in the `session_data` table, with each variable in its own row of
+/// `session_values`, so concurrent writes to different variables of one session never
+/// undo each other.
+pub struct MysqlStore;
+
+const TABLE: &str = "session_data";
+const VALUES: &str = "session_values";
+
+/// The `session_values` row of one variable.
+fn value_id(id: &str, key: &str) -> String {
+    format!("{}:{}", id, key)
+}
+
+/// Finds the row whose first column is exactly `id`, as MySQL's comparison may ignore case.
+fn exact_row(rows: Vec<Vec<Value>>, id: &str) -> Option<Vec<Value>> {
+    rows.into_iter()
+        .find(|t| from_value::<String>(t[0].clone()) == id)
+}
+
+impl MysqlStore {
+    /// Whether session `id` exists and has not expired. Ids come from clients, so they are
+    /// looked up exactly.
+    async fn is_live(id: &str) -> bool {
+        match exact_row(get_where(TABLE, "id", id).await, id) {
+            Some(row) => from_value::<u64>(row[1].clone()) > crate::unix_time(),
+            None => false,
+        }
+    }
+
+    async fn forget(id: &str) {
+        delete_row_where(VALUES, "session", id).await;
+        delete_row_where(TABLE, "id", id).await;
+    }
+}
+
+impl SessionStore for MysqlStore {
+    fn create<'a>(&'a self, id: &'a str, expires: u64) -> StoreFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            insert_row(TABLE, vec!["id", "expires"], vec![id, &expires.to_string()]).await
+        })
+    }
+
+    fn exists<'a>(&'a self, id: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(MysqlStore::is_live(id))
+    }
+
+    fn get<'a>(&'a self, id: &'a str, key: &'a str) -> StoreFuture<'a, Option<String>> {
+        Box::pin(async move {
+            if !MysqlStore::is_live(id).await {
+                return None;
+            }
+            let value_id = value_id(id, key);
+            let row = exact_row(get_where(VALUES, "id", &value_id).await, &value_id)?;
+            Some(from_value(row[2].clone()))
+        })
+    }
+
+    /// Inserts the variable's row, or updates it if the insert finds one already there.
+    fn set<'a>(&'a self, id: &'a str, key: &'a str, value: String) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            if !MysqlStore::is_live(id).await {
+                return;
+            }
+            let value_id = value_id(id, key);
+            let inserted = insert_row(
+                VALUES,
+                vec!["id", "session", "value"],
+                vec![&value_id, id, &value],
+            )
+            .await;
+            if inserted.is_err() {
+                change_row_where(VALUES, "id", &value_id, "value", &value).await;
+            }
+        })
+    }
+
+    fn clear<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move { delete_row_where(VALUES, "session", id).await })
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(MysqlStore::forget(id))
+    }
+
+    fn sweep(&self, now: u64) -> StoreFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut expired = Vec::new();
+            for row in get_all_rows(TABLE, false).await {
+                if from_value::<u64>(row[1].clone()) <= now {
+                    let id = from_value::<String>(row[0].clone());
+                    MysqlStore::forget(&id).await;
+                    expired.push(id);
+                }
+            }
+            expired
+        })
+    }
+
+    fn count(&self, now: u64) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            get_all_rows(TABLE, false)
+                .await
+                .iter()
+                .filter(|t| from_value::<u64>(t[1].clone()) > now)
+                .count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store_keeps_variables_until_cleared_or_deleted() {
+        let store = MemoryStore::default();
+        let expires = crate::unix_time() + 60;
+        store.create("a", expires).await.unwrap();
+        assert!(store.exists("a").await);
+        assert_eq!(store.get("a", "email").await, None);
+        store.set("a", "email", "a@example.com".to_string()).await;
+        assert_eq!(
+            store.get("a", "email").await.as_deref(),
+            Some("a@example.com")
+        );
+        store.clear("a").await;
+        assert_eq!(store.get("a", "email").await, None);
+        assert!(store.exists("a").await);
+        store.delete("a").await;
+        assert!(!store.exists("a").await);
+        store.set("a", "email", "a@example.com".to_string()).await;
+        assert_eq!(store.get("a", "email").await, None);
+    }
+
+    #[tokio::test]
+    async fn memory_store_hides_expired_sessions_before_the_sweep() {
+        let store = MemoryStore::default();
+        store.create("old", crate::unix_time() - 1).await.unwrap();
+        store.set("old", "email", "a@example.com".to_string()).await;
+        assert!(!store.exists("old").await);
+        assert_eq!(store.get("old", "email").await, None);
+    }
+
+    #[tokio::test]
+    async fn memory_store_sweep_returns_the_expired_ids() {
+        let store = MemoryStore::default();
+        let now = crate::unix_time();
+        store.create("old", now - 1).await.unwrap();
+        store.create("ending", now).await.unwrap();
+        store.create("live", now + 60).await.unwrap();
+        assert_eq!(store.count(now).await, 1);
+        let mut expired = store.sweep(now).await;
+        expired.sort();
+        assert_eq!(expired, vec!["ending".to_string(), "old".to_string()]);
+        assert!(store.sweep(now).await.is_empty());
+        assert!(store.exists("live").await);
+        assert_eq!(store.count(now).await, 1);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::auth::Client;
+use crate::db::*;
+use crate::error::ApiError;
+use crate::responses::SessionInfo;
+use crate::session_store::Session;
+
+/// How often, in seconds, a session's last seen time, address and device are written back.
+const TOUCH_INTERVAL: u64 = 60;
+/// User agents are cut to this many characters before they are stored.
+const MAX_DEVICE_LENGTH: usize = 200;
+
+/// The `account_sessions` key of an admin, kept apart from the ids of the `users` table.
+pub fn admin_account(id: &str) -> String {
+    format!("admin:{}", id)
+}
+
+pub fn user_account(id: &str) -> String {
+    format!("user:{}", id)
+}
+
+/// Records that a session is logged in to `account`, so it can be listed and revoked.
+/// A session which is already recorded for `account` keeps its row.
+pub async fn register(session_id: &str, account: &str) -> Result<(), ApiError> {
+    if let Some(row) = get_where("account_sessions", "session", session_id)
+        .await
+        .first()
+    {
+        if from_value::<String>(row[1].clone()) == account {
+            return Ok(());
+        }
+    }
+    let now = crate::unix_time().to_string();
+    delete_row_where("account_sessions", "session", session_id).await;
+    insert_row(
+        "account_sessions",
+        vec!["session", "account", "created", "handle", "last_seen"],
+        vec![session_id, account, &now, &crate::generate_token(16), &now],
+    )
+    .await
+    .map_err(ApiError::Database)
+}
+
+/// Notes that a logged in session was just used, at most once every `TOUCH_INTERVAL`.
+pub async fn touch(session: &mut Session, client: &Client) {
+    let now = crate::unix_time();
+    let last_seen = session
+        .get("last_seen")
+        .await
+        .and_then(|t| t.parse::<u64>().ok())
+        .unwrap_or_default();
+    if now.saturating_sub(last_seen) < TOUCH_INTERVAL {
+        return;
+    }
+    session.set("last_seen", now.to_string()).await;
+    let id = session.get_id();
+    let ip = client.ip.map(|t| t.to_string()).unwrap_or_default();
+    let device: String = client.device.chars().take(MAX_DEVICE_LENGTH).collect();
+    for (column, value) in &[
+        ("last_seen", now.to_string()),
+        ("ip", ip),
+        ("device", device),
+    ] {
+        change_row_where("account_sessions", "session", &id, column, value).await;
+    }
+}
+
+/// Drops a session which was logged out from the table.
+pub async fn forget(session_id: &str) {
+    delete_row_where("account_sessions", "session", session_id).await;
+}
+
+/// Logs out a session and drops it from the table.
+pub async fn end(mut session: Session) {
+    forget(&session.get_id()).await;
+    session.delete().await;
+}
+
+async fn delete(session_id: &str) {
+    match Session::from_id(session_id).await {
+        Some(session) => end(session).await,
+        None => forget(session_id).await,
+    }
+}
+
+/// Logs out every session of `account` except `keep`.
+pub async fn revoke_all(account: &str, keep: &str) {
+    for row in get_like("account_sessions", "account", account).await {
+        let id = from_value::<String>(row[0].clone());
+        if id != keep {
+            delete(&id).await;
+        }
+    }
+}
+
+/// Logs out the session of `account` listed under `handle`.
+pub async fn revoke(account: &str, handle: &str) -> Result<(), ApiError> {
+    let row = get_where("account_sessions", "handle", handle)
+        .await
+        .into_iter()
+        .find(|t| {
+            from_value::<String>(t[1].clone()) == account
+                && from_value::<String>(t[3].clone()) == handle
+        })
+        .ok_or_else(|| ApiError::NotFound("This session does not exist.".to_string()))?;
+    delete(&from_value::<String>(row[0].clone())).await;
+    Ok(())
+}
+
+/// Every session of `account`, marking the one with id `current`.
+pub async fn list(account: &str, current: &str) -> Vec<SessionInfo> {
+    get_like("account_sessions", "account", account)
+        .await
+        .into_iter()
+        .map(|row| SessionInfo {
+            current: from_value::<String>(row[0].clone()) == current,
+            created: from_value(row[2].clone()),
+            handle: from_value(row[3].clone()),
+            ip: from_value(row[4].clone()),
+            device: from_value(row[5].clone()),
+            last_seen: from_value(row[6].clone()),
+        })
+        .collect()
+}
@@ -0,0 +1,268 @@
+use chrono::{Datelike, NaiveDate};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Statement};
+
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::db::{DbFuture, Repository, Value};
+use crate::locks;
+
+/// A [`Repository`] over a SQLite database, so the api can run without a MySQL server,
+/// e.g. in integration tests. Queries run on the calling thread.
+pub struct SqliteRepository {
+    connection: Mutex<Connection>,
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Converts a SQLite value to the mysql value the MySQL server would have sent. Text in
+/// `DATE` columns becomes a date, as `from_value` expects.
+fn to_value(value: ValueRef, declared_type: &str) -> Value {
+    match value {
+        ValueRef::Null => Value::NULL,
+        ValueRef::Integer(t) => Value::Int(t),
+        ValueRef::Real(t) => Value::Double(t),
+        ValueRef::Text(t) | ValueRef::Blob(t) => {
+            let date = std::str::from_utf8(t)
+                .ok()
+                .filter(|_| declared_type.contains("date"))
+                .and_then(|t| NaiveDate::parse_from_str(t, "%Y-%m-%d").ok());
+            match date {
+                Some(date) => Value::Date(
+                    date.year() as u16,
+                    date.month() as u8,
+                    date.day() as u8,
+                    0,
+                    0,
+                    0,
+                    0,
+                ),
+                None => Value::Bytes(t.to_vec()),
+            }
+        }
+    }
+}
+
+fn read_rows(statement: &mut Statement, params: &[&str]) -> rusqlite::Result<Vec<Vec<Value>>> {
+    let declared_types: Vec<String> = statement
+        .columns()
+        .iter()
+        .map(|t| t.decl_type().unwrap_or_default().to_lowercase())
+        .collect();
+    let mut rows = statement.query(params)?;
+    let mut values = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut row_values = Vec::new();
+        for (i, declared_type) in declared_types.iter().enumerate() {
+            row_values.push(to_value(row.get_raw(i), declared_type));
+        }
+        values.push(row_values);
+    }
+    Ok(values)
+}
+
+impl SqliteRepository {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        Connection::open(path)
+            .map(SqliteRepository::new)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn in_memory() -> Result<Self, String> {
+        Connection::open_in_memory()
+            .map(SqliteRepository::new)
+            .map_err(|e| e.to_string())
+    }
+
+    fn new(connection: Connection) -> Self {
+        SqliteRepository {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        locks::lock(&self.connection)
+    }
+
+    /// Runs statements such as the `CREATE TABLE`s of a test schema.
+    pub fn execute_batch(&self, sql: &str) -> Result<(), String> {
+        self.lock().execute_batch(sql).map_err(|e| e.to_string())
+    }
+
+    fn query(&self, sql: &str, params: &[&str]) -> Vec<Vec<Value>> {
+        let connection = self.lock();
+        let rows = connection
+            .prepare(sql)
+            .and_then(|mut t| read_rows(&mut t, params));
+        rows.unwrap_or_else(|e| {
+            tracing::error!(error = %e, sql, "sqlite query failed");
+            Vec::new()
+        })
+    }
+
+    fn execute(&self, sql: &str, params: &[&str]) -> Result<(), String> {
+        self.lock()
+            .execute(sql, params)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Runs a statement whose errors the mysql helpers would only have logged.
+    fn execute_logged(&self, sql: &str, params: &[&str]) {
+        if let Err(e) = self.execute(sql, params) {
+            tracing::error!(error = %e, sql, "sqlite statement failed");
+        }
+    }
+
+    fn query_id(&self, sql: &str) -> i32 {
+        match self.query(sql, &[]).first().map(|t| &t[0]) {
+            Some(Value::Int(t)) => *t as i32,
+            _ => 0,
+        }
+    }
+}
+
+impl Repository for SqliteRepository {
+    fn get_like<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, Vec<Vec<Value>>> {
+        let sql = format!(
+            "SELECT * FROM {} WHERE {} LIKE ?",
+            quote(table),
+            quote(column)
+        );
+        let rows = self.query(&sql, &[value]);
+        Box::pin(async move { rows })
+    }
+
+    fn get_where<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, Vec<Vec<Value>>> {
+        let sql = format!("SELECT * FROM {} WHERE {} = ?", quote(table), quote(column));
+        let rows = self.query(&sql, &[value]);
+        Box::pin(async move { rows })
+    }
+
+    fn get_all_rows<'a>(&'a self, table: &'a str, sorted: bool) -> DbFuture<'a, Vec<Vec<Value>>> {
+        let mut sql = format!("SELECT * FROM {}", quote(table));
+        if sorted {
+            sql.push_str(" ORDER BY id");
+        }
+        let rows = self.query(&sql, &[]);
+        Box::pin(async move { rows })
+    }
+
+    fn get_some<'a>(&'a self, table: &'a str, column: &'a str) -> DbFuture<'a, Vec<Vec<Value>>> {
+        let sql = format!("SELECT {} FROM {}", quote(column), quote(table));
+        let rows = self.query(&sql, &[]);
+        Box::pin(async move { rows })
+    }
+
+    fn get_column_details<'a>(&'a self, table: &'a str) -> DbFuture<'a, Vec<Vec<Value>>> {
+        // table_info gives cid, name, type, notnull, dflt_value and pk.
+        let sql = format!("PRAGMA table_info({})", quote(table));
+        let columns = self
+            .query(&sql, &[])
+            .into_iter()
+            .map(|mut t| {
+                let column_type = match t.remove(2) {
+                    Value::Bytes(t) => {
+                        Value::Bytes(String::from_utf8_lossy(&t).to_lowercase().into_bytes())
+                    }
+                    t => t,
+                };
+                vec![t.remove(1), column_type]
+            })
+            .collect();
+        Box::pin(async move { columns })
+    }
+
+    fn insert_row<'a>(
+        &'a self,
+        table: &'a str,
+        names: Vec<&'a str>,
+        values: Vec<&'a str>,
+    ) -> DbFuture<'a, Result<(), String>> {
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote(table),
+            names
+                .iter()
+                .map(|t| quote(t))
+                .collect::<Vec<_>>()
+                .join(", "),
+            vec!["?"; values.len()].join(", ")
+        );
+        let result = self.execute(&sql, &values);
+        Box::pin(async move { result })
+    }
+
+    fn change_row_where<'a>(
+        &'a self,
+        table: &'a str,
+        where_column: &'a str,
+        where_value: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, ()> {
+        let sql = format!(
+            "UPDATE {} SET {} = ? WHERE {} = ?",
+            quote(table),
+            quote(column),
+            quote(where_column)
+        );
+        self.execute_logged(&sql, &[value, where_value]);
+        Box::pin(async {})
+    }
+
+    fn delete_row_where<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, ()> {
+        let sql = format!("DELETE FROM {} WHERE {} = ?", quote(table), quote(column));
+        self.execute_logged(&sql, &[value]);
+        Box::pin(async {})
+    }
+
+    fn get_max_id<'a>(&'a self, table: &'a str) -> DbFuture<'a, i32> {
+        let id = self.query_id(&format!(
+            "SELECT COALESCE(MAX(id), 0) FROM {}",
+            quote(table)
+        ));
+        Box::pin(async move { id })
+    }
+
+    fn get_min_id<'a>(&'a self, table: &'a str) -> DbFuture<'a, i32> {
+        let id = self.query_id(&format!(
+            "SELECT COALESCE(MIN(id), 0) FROM {}",
+            quote(table)
+        ));
+        Box::pin(async move { id })
+    }
+
+    fn row_exists<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        value: &'a str,
+    ) -> DbFuture<'a, bool> {
+        let sql = format!(
+            "SELECT 1 FROM {} WHERE {} = ? LIMIT 1",
+            quote(table),
+            quote(column)
+        );
+        let exists = !self.query(&sql, &[value]).is_empty();
+        Box::pin(async move { exists })
+    }
+}
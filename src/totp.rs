@@ -0,0 +1,127 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use rand::{thread_rng, RngCore};
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+const ISSUER: &str = "OLMMCC";
+const STEP_SECONDS: u64 = 30;
+const DIGITS: usize = 6;
+/// Codes from one step either side of now are accepted, to allow for clock drift.
+const DRIFT_STEPS: u64 = 1;
+
+/// A new random shared secret, base32 encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut secret = [0; 20];
+    thread_rng().fill_bytes(&mut secret);
+    base32::encode(ALPHABET, &secret)
+}
+
+/// Escapes everything but unreserved characters, for the label of an `otpauth://` URI.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|t| match t {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (t as char).to_string()
+            }
+            _ => format!("%{:02X}", t),
+        })
+        .collect()
+}
+
+/// The `otpauth://` URI an authenticator app reads from a QR code.
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    let query = serde_urlencoded::to_string([
+        ("secret", secret),
+        ("issuer", ISSUER),
+        ("algorithm", "SHA1"),
+        ("digits", &DIGITS.to_string()),
+        ("period", &STEP_SECONDS.to_string()),
+    ])
+    .unwrap_or_default();
+    format!(
+        "otpauth://totp/{}:{}?{}",
+        percent_encode(ISSUER),
+        percent_encode(account),
+        query
+    )
+}
+
+/// The RFC 4226 code for one counter value.
+fn code_at(key: &[u8], counter: u64) -> String {
+    let mut mac = HmacSha1::new_varkey(key).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |t, (a, b)| t | (a ^ b)) == 0
+}
+
+/// Checks a code against `secret` at unix time `now`. Returns the time step it belongs
+/// to, so callers can refuse a step which was already used.
+pub fn verify(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let key = base32::decode(ALPHABET, secret)?;
+    let code = code.trim();
+    let current = now / STEP_SECONDS;
+    (current.saturating_sub(DRIFT_STEPS)..=current + DRIFT_STEPS)
+        .find(|step| constant_time_eq(code_at(&key, *step).as_bytes(), code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret of RFC 6238 appendix B, "12345678901234567890".
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn verify_accepts_the_rfc_6238_vectors() {
+        // The RFC lists 8 digit codes; these are their last 6 digits.
+        let vectors = [
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+            (20_000_000_000, "353130"),
+        ];
+        for (time, code) in vectors.iter() {
+            assert_eq!(verify(SECRET, code, *time), Some(time / STEP_SECONDS));
+        }
+    }
+
+    #[test]
+    fn verify_allows_one_step_of_drift() {
+        assert_eq!(verify(SECRET, "287082", 59 + 30), Some(1));
+        assert_eq!(verify(SECRET, "287082", 59 - 30), Some(1));
+        assert_eq!(verify(SECRET, "287082", 59 + 60), None);
+    }
+
+    #[test]
+    fn verify_rejects_wrong_codes_and_secrets() {
+        assert_eq!(verify(SECRET, "287083", 59), None);
+        assert_eq!(verify(SECRET, "28708", 59), None);
+        assert_eq!(verify("not base32!", "287082", 59), None);
+    }
+
+    #[test]
+    fn provisioning_uri_escapes_the_label() {
+        assert!(provisioning_uri(SECRET, "a b@example.com")
+            .starts_with("otpauth://totp/OLMMCC:a%20b%40example.com?secret="));
+    }
+}
@@ -0,0 +1,406 @@
+//! Runs real routes against an in-memory SQLite database. Needs `--features sqlite`.
+#![cfg(feature = "sqlite")]
+
+use scrypt::{scrypt_simple, ScryptParams};
+use serde_json::{json, Value};
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use olmmcc::auth::Client;
+use olmmcc::db::{self, Repository};
+use olmmcc::session_store::{MysqlStore, SessionStore};
+use olmmcc::sqlite::SqliteRepository;
+use olmmcc::{ApiError, AuthContext, Config};
+
+/// The tables the tested routes touch, with the columns in the same order as MySQL.
+const SCHEMA: &str = "
+    CREATE TABLE users (
+        email TEXT NOT NULL,
+        id INTEGER PRIMARY KEY,
+        subscription_policy INTEGER NOT NULL,
+        password TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE admin (
+        email TEXT NOT NULL,
+        password TEXT NOT NULL DEFAULT '',
+        id INTEGER PRIMARY KEY,
+        subscription_policy INTEGER NOT NULL DEFAULT 1,
+        refresh_token TEXT NOT NULL DEFAULT '',
+        permissions TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE admin_totp (
+        admin_id INTEGER PRIMARY KEY,
+        secret TEXT NOT NULL,
+        recovery_codes TEXT NOT NULL,
+        last_step INTEGER NOT NULL,
+        failures INTEGER NOT NULL DEFAULT 0,
+        locked_until INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE account_sessions (
+        session TEXT PRIMARY KEY,
+        account TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        handle TEXT NOT NULL,
+        ip TEXT NOT NULL DEFAULT '',
+        device TEXT NOT NULL DEFAULT '',
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE login_links (
+        token TEXT PRIMARY KEY,
+        session TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE TABLE session_data (
+        id TEXT PRIMARY KEY,
+        expires INTEGER NOT NULL
+    );
+    CREATE TABLE session_values (
+        id TEXT PRIMARY KEY,
+        session TEXT NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE songs (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        link TEXT NOT NULL,
+        role TEXT NOT NULL DEFAULT '',
+        article TEXT NOT NULL
+    );
+";
+
+/// A fresh database for one test, which runs inside its [`db::scope`]. Sessions stay in
+/// the default memory store.
+fn database() -> Arc<dyn Repository> {
+    let repository = SqliteRepository::in_memory().unwrap();
+    repository.execute_batch(SCHEMA).unwrap();
+    Arc::new(repository)
+}
+
+fn config() -> Config {
+    Config {
+        code_secret: "integration tests".to_string(),
+        ..Config::default()
+    }
+}
+
+fn hash(password: &str) -> String {
+    // Cheap parameters, as scrypt_check reads them back from the hash.
+    scrypt_simple(password, &ScryptParams::new(4, 8, 1).unwrap()).unwrap()
+}
+
+async fn call(config: &Config, path: &str, body: Value) -> Result<Value, ApiError> {
+    let router = olmmcc::router();
+    let route = router.find(path).expect("the route exists");
+    let auth = AuthContext::resolve(&body, Client::default()).await;
+    let response = route.call(config, auth, &body).await?;
+    Ok(serde_json::from_str(&response).unwrap())
+}
+
+async fn add_admin(email: &str, password: &str, permissions: &str) {
+    db::insert_row(
+        "admin",
+        vec!["email", "password", "permissions"],
+        vec![email, &hash(password), permissions],
+    )
+    .await
+    .unwrap();
+}
+
+async fn admin_session(config: &Config, email: &str, password: &str) -> String {
+    let response = call(
+        config,
+        "/admin_login",
+        json!({ "email": email, "password": password }),
+    )
+    .await
+    .unwrap();
+    response["session"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn signup_stores_the_user_before_mailing_the_code() {
+    db::scope(database(), async {
+        let config = config();
+        // No admin has connected Gmail, so the code cannot be sent.
+        let result = call(&config, "/signup", json!({ "email": "New@Example.com" })).await;
+        assert!(matches!(result, Err(ApiError::Mail(_))));
+        let users = db::get_where("users", "email", "new@example.com").await;
+        assert_eq!(users.len(), 1);
+        assert_eq!(db::from_value::<i32>(users[0][2].clone()), 1);
+
+        let result = call(&config, "/signup", json!({ "email": "new@example.com" })).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+    })
+    .await;
+}
+#[tokio::test]
+async fn password_login_checks_the_hash_and_registers_the_session() {
+    db::scope(database(), async {
+        let config = config();
+        db::insert_row(
+            "users",
+            vec!["email", "subscription_policy", "password"],
+            vec!["user@example.com", "1", &hash("correct horse")],
+        )
+        .await
+        .unwrap();
+
+        let wrong = json!({ "email": "user@example.com", "password": "wrong horse" });
+        let result = call(&config, "/password_login", wrong).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+
+        // Emails are matched exactly, so a pattern cannot stand in for an address.
+        let pattern = json!({ "email": "user%", "password": "correct horse" });
+        let result = call(&config, "/password_login", pattern).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+
+        let right = json!({ "email": "User@Example.com", "password": "correct horse" });
+        let response = call(&config, "/password_login", right).await.unwrap();
+        let session = response["session"].as_str().unwrap();
+        let sessions = call(&config, "/list_sessions", json!({ "session": session }))
+            .await
+            .unwrap();
+        assert_eq!(sessions.as_array().unwrap().len(), 1);
+        assert_eq!(sessions[0]["current"], true);
+    })
+    .await;
+}
+#[tokio::test]
+async fn set_password_logs_out_the_other_sessions() {
+    db::scope(database(), async {
+        let config = config();
+        db::insert_row(
+            "users",
+            vec!["email", "subscription_policy", "password"],
+            vec!["user@example.com", "1", &hash("correct horse")],
+        )
+        .await
+        .unwrap();
+        let login = json!({ "email": "user@example.com", "password": "correct horse" });
+        let mut sessions = Vec::new();
+        for _ in 0..2 {
+            let response = call(&config, "/password_login", login.clone())
+                .await
+                .unwrap();
+            sessions.push(response["session"].as_str().unwrap().to_string());
+        }
+
+        let change = json!({
+            "session": sessions[0],
+            "current_password": "correct horse",
+            "password": "battery staple",
+        });
+        call(&config, "/set_password", change).await.unwrap();
+        let listed = call(&config, "/list_sessions", json!({ "session": sessions[0] }))
+            .await
+            .unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        let result = call(&config, "/list_sessions", json!({ "session": sessions[1] })).await;
+        assert!(result.is_err());
+    })
+    .await;
+}
+#[tokio::test]
+async fn admin_login_rejects_unknown_emails_and_wrong_passwords() {
+    db::scope(database(), async {
+        let config = config();
+        add_admin("login@example.com", "admin password", "").await;
+        let wrong = json!({ "email": "login@example.com", "password": "user password" });
+        let result = call(&config, "/admin_login", wrong).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+
+        let unknown = json!({ "email": "nobody@example.com", "password": "admin password" });
+        let result = call(&config, "/admin_login", unknown).await;
+        assert!(matches!(result, Err(ApiError::NotAuthorized(_))));
+
+        let pattern = json!({ "email": "login%", "password": "admin password" });
+        let result = call(&config, "/admin_login", pattern).await;
+        assert!(matches!(result, Err(ApiError::NotAuthorized(_))));
+
+        let session = admin_session(&config, "login@example.com", "admin password").await;
+        assert!(!session.is_empty());
+    })
+    .await;
+}
+#[tokio::test]
+async fn change_row_needs_the_table_permission() {
+    db::scope(database(), async {
+        let config = config();
+        add_admin("songs@example.com", "admin password", "songs:write").await;
+        db::insert_row(
+            "songs",
+            vec!["name", "link", "article"],
+            vec!["Old", "https://example.com/song", "Spring"],
+        )
+        .await
+        .unwrap();
+        let id = db::get_max_id("songs").await.to_string();
+        let session = admin_session(&config, "songs@example.com", "admin password").await;
+
+        let change = json!({
+            "session": session,
+            "table": "songs",
+            "id": id,
+            "name": "name",
+            "value": "New",
+        });
+        let response = call(&config, "/change_row", change).await.unwrap();
+        assert_eq!(response["success"], true);
+        let song = db::get_where("songs", "id", &id).await;
+        assert_eq!(db::from_value::<String>(song[0][1].clone()), "New");
+
+        let change = json!({
+            "session": session,
+            "table": "users",
+            "id": "1",
+            "name": "subscription_policy",
+            "value": "0",
+        });
+        let result = call(&config, "/change_row", change).await;
+        assert!(matches!(result, Err(ApiError::NotAuthorized(_))));
+    })
+    .await;
+}
+#[tokio::test]
+async fn get_database_never_sends_secret_columns() {
+    db::scope(database(), async {
+        let config = config();
+        add_admin("manager@example.com", "admin password", "admin:manage").await;
+        let session = admin_session(&config, "manager@example.com", "admin password").await;
+
+        let body = json!({ "session": session, "table": "admin" });
+        let response = call(&config, "/get_database", body).await.unwrap();
+        let columns = response["columns"].as_array().unwrap();
+        assert!(!columns.contains(&json!("password")));
+        assert!(!columns.contains(&json!("refresh_token")));
+        for row in response["rows"].as_array().unwrap() {
+            assert_eq!(row.as_array().unwrap().len(), columns.len());
+            assert!(!row.to_string().contains("$rscrypt$"));
+        }
+    })
+    .await;
+}
+#[tokio::test]
+async fn grant_permission_changes_only_the_named_admin() {
+    db::scope(database(), async {
+        let config = config();
+        add_admin("granter@example.com", "admin password", "admin:manage").await;
+        add_admin("editor@example.com", "admin password", "").await;
+        let session = admin_session(&config, "granter@example.com", "admin password").await;
+
+        let grant = json!({ "session": session, "email": "%", "permission": "mail:send" });
+        let result = call(&config, "/grant_permission", grant).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+
+        let grant = json!({
+            "session": session,
+            "email": "Editor@Example.com",
+            "permission": "songs:write",
+        });
+        let response = call(&config, "/grant_permission", grant).await.unwrap();
+        assert_eq!(response["email"], "editor@example.com");
+        let editor = db::get_where("admin", "email", "editor@example.com").await;
+        assert_eq!(
+            db::from_value::<String>(editor[0][5].clone()),
+            "songs:write"
+        );
+    })
+    .await;
+}
+#[tokio::test]
+async fn wrong_authenticator_codes_lock_the_admin_across_logins() {
+    db::scope(database(), async {
+        let config = config();
+        add_admin("totp@example.com", "admin password", "").await;
+        let id = db::get_where("admin", "email", "totp@example.com").await[0][2].clone();
+        db::insert_row(
+            "admin_totp",
+            vec!["admin_id", "secret", "recovery_codes", "last_step"],
+            vec![
+                &db::from_value::<i32>(id).to_string(),
+                "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ",
+                "",
+                "0",
+            ],
+        )
+        .await
+        .unwrap();
+        let login = json!({ "email": "totp@example.com", "password": "admin password" });
+
+        let mut locked = false;
+        for _ in 0..config.rate_limit.max_code_attempts {
+            // Every attempt starts a new login, which must not reset the count.
+            let session = match call(&config, "/admin_login", login.clone()).await {
+                Err(ApiError::TotpRequired { session }) => session,
+                other => panic!("expected totp_required, got {:?}", other),
+            };
+            let verify = json!({ "session": session, "code": "not a code" });
+            match call(&config, "/verify_totp", verify).await {
+                Err(ApiError::Code(_)) => {}
+                Err(ApiError::RateLimited { .. }) => locked = true,
+                other => panic!("expected a wrong code, got {:?}", other),
+            }
+        }
+        assert!(locked);
+    })
+    .await;
+}
+#[tokio::test]
+async fn mysql_store_keeps_each_variable_separately() {
+    db::scope(database(), async {
+        let store = MysqlStore;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        store.create("Store1", now + 60).await.unwrap();
+        assert!(store.exists("Store1").await);
+        assert!(!store.exists("store1").await);
+
+        store.set("Store1", "code", "hash".to_string()).await;
+        store.set("Store1", "attempts", "1".to_string()).await;
+        store.set("Store1", "attempts", "2".to_string()).await;
+        assert_eq!(store.get("Store1", "code").await.as_deref(), Some("hash"));
+        assert_eq!(store.get("Store1", "attempts").await.as_deref(), Some("2"));
+        assert_eq!(store.get("store1", "code").await, None);
+
+        store.clear("Store1").await;
+        assert_eq!(store.get("Store1", "code").await, None);
+        assert!(store.exists("Store1").await);
+
+        store.create("Store2", now - 1).await.unwrap();
+        store.set("Store2", "code", "hash".to_string()).await;
+        assert!(!store.exists("Store2").await);
+        assert_eq!(store.get("Store2", "code").await, None);
+        assert!(store.sweep(now).await.contains(&"Store2".to_string()));
+
+        store.delete("Store1").await;
+        assert!(!store.exists("Store1").await);
+    })
+    .await;
+}
+#[tokio::test]
+async fn sweep_deletes_expired_login_links() {
+    db::scope(database(), async {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for (token, expires) in &[("expired", now - 1), ("waiting", now + 60)] {
+            db::insert_row(
+                "login_links",
+                vec!["token", "session", "expires"],
+                vec![token, "session", &expires.to_string()],
+            )
+            .await
+            .unwrap();
+        }
+
+        olmmcc::session_store::sweep().await;
+        let links = db::get_all_rows("login_links", false).await;
+        assert_eq!(links.len(), 1);
+        assert_eq!(db::from_value::<String>(links[0][0].clone()), "waiting");
+    })
+    .await;
+}